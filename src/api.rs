@@ -1,9 +1,16 @@
 // generic API trait for driving metadata fetch
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt::{self, Display},
+    thread,
+    time::Duration,
 };
 
+use reqwest::{
+    StatusCode,
+    blocking::{Client, RequestBuilder, Response},
+    header,
+};
 use toml::Table;
 
 use urlencoding;
@@ -35,6 +42,29 @@ impl fmt::Display for ModResult {
     }
 }
 
+#[derive(Debug)]
+pub struct ModVersion {
+    pub id: String,
+    pub number: String,
+    pub channel: String, // release, beta or alpha
+    pub published: String,
+    pub game_versions: Vec<String>,
+}
+
+impl fmt::Display for ModVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}) [{}] published {}\n\tgame versions: {}",
+            self.number,
+            self.id,
+            self.channel,
+            self.published,
+            self.game_versions.join(" ")
+        )
+    }
+}
+
 // pub struct Mod {
 //     id: String,
 //     slug: String,
@@ -50,21 +80,54 @@ pub struct DownloadOptions {
     dependencies: Vec<String>, // slugs
 }
 
+/// what to do with dependencies a package can work without
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OptionalDeps {
+    Ask,
+    All,
+    Skip,
+}
+
 pub trait APIDriver {
     // fn configure(&mut self, cfg: &Table) -> Result<(), String>;
 
-    fn search(&self, query: &str) -> Result<Vec<ModResult>, String>;
+    fn search(&self, query: &str, kind: Kind) -> Result<Vec<ModResult>, String>;
 
+    /// derivations for packages, given as (package id, optional version id), and everything they
+    /// require that is not already seen
     fn get_derivations_for(
         &self,
-        pkg_id: &str,
+        packages: &[(String, Option<String>)],
         seen: &mut Vec<(String, Option<String>)>,
+        optional: OptionalDeps,
         hash: bool,
         store: &Store,
     ) -> Result<Vec<Derivation>, String>;
+
+    /// versions of a package compatible with the pack, newest first
+    fn list_versions(&self, pkg_id: &str) -> Result<Vec<ModVersion>, String>;
+
+    /// version id -> version number, for derivations written before versions were recorded
+    fn version_numbers(&self, version_ids: &[String]) -> Result<HashMap<String, String>, String>;
+
+    /// newer versions of the managed derivations, as (package id, version id)
+    fn find_updates(
+        &self,
+        derivations: &[Derivation],
+        store: &Store,
+    ) -> Result<Vec<(String, String)>, String>;
 }
 
-use crate::{api_driver::modrinth::ModrinthDriver, package::Derivation, store::Store};
+use crate::{
+    api_driver::modrinth::ModrinthDriver,
+    http_cache::http_cache,
+    package::{Derivation, Kind},
+    progress,
+    store::Store,
+    util,
+};
+
+const RATE_LIMIT_RETRIES: u32 = 3;
 pub fn get_api_driver(name: &str, cfg: &Table) -> Result<Box<dyn APIDriver>, String> {
     // ADD DRIVERS HERE
     match name {
@@ -76,9 +139,9 @@ pub fn get_api_driver(name: &str, cfg: &Table) -> Result<Box<dyn APIDriver>, Str
 }
 
 pub struct HTTPSQuery {
-    hostname: String,
+    base_url: String, // scheme and host, e.g. https://api.modrinth.com
     endpoint: String,
-    parameters: HashMap<String, String>,
+    parameters: BTreeMap<String, String>, // sorted so equal queries produce equal urls
 }
 impl HTTPSQuery {
     pub fn serialize_array(array: &[&dyn Display]) -> String {
@@ -94,11 +157,11 @@ impl HTTPSQuery {
         s.push(']');
         s
     }
-    pub fn new(hostname: &str, endpoint: &str) -> Self {
+    pub fn new(base_url: &str, endpoint: &str) -> Self {
         Self {
-            hostname: hostname.to_string(),
+            base_url: base_url.trim_end_matches('/').to_string(),
             endpoint: endpoint.to_string(),
-            parameters: HashMap::new(),
+            parameters: BTreeMap::new(),
         }
     }
     pub fn add_parameter(mut self, parameter: &str, value: &str) -> Result<Self, String> {
@@ -108,7 +171,7 @@ impl HTTPSQuery {
     }
 
     pub fn formulate(&self) -> String {
-        let base = format!("https://{}/{}?", self.hostname, self.endpoint);
+        let base = format!("{}/{}", self.base_url, self.endpoint);
         let mut parameter_str = String::new();
         for (parameter, value) in &self.parameters {
             if !parameter_str.is_empty() {
@@ -120,16 +183,191 @@ impl HTTPSQuery {
                 urlencoding::encode(value)
             ));
         }
-        base + &parameter_str
+        if parameter_str.is_empty() {
+            base
+        } else {
+            format!("{base}?{parameter_str}")
+        }
     }
 
-    pub fn send(&self) -> Result<String, String> {
+    pub fn send(&self) -> Result<String, HTTPError> {
         let url = self.formulate();
+        let cache = http_cache();
+        let cached = cache.and_then(|cache| cache.lookup(&url));
+        if util::is_offline() {
+            return cached
+                .map(|cached| cached.body)
+                .ok_or(HTTPError::Request(format!(
+                    "cannot query `{url}` in offline mode, it was never cached"
+                )));
+        }
+        if let (Some(cache), Some(cached)) = (cache, &cached)
+            && cache.is_fresh(cached)
+        {
+            return Ok(cached.body.clone());
+        }
         // println!("URL: {url}");
-        let response = reqwest::blocking::get(url)
-            .map_err(|e| format!("web request failure: {e}"))?
+        let client = Client::new();
+        let response = self.execute(|| {
+            let request = client.get(&url);
+            match cached.as_ref().and_then(|cached| cached.etag.as_ref()) {
+                Some(etag) => request.header(header::IF_NONE_MATCH, etag),
+                None => request,
+            }
+        })?;
+        let status = response.status();
+        if status == StatusCode::NOT_MODIFIED
+            && let (Some(cache), Some(cached)) = (cache, cached)
+        {
+            cache.touch(&cached)?;
+            return Ok(cached.body);
+        }
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(|etag| etag.to_string());
+        let response = response
+            .text()
+            .map_err(|e| HTTPError::Request(format!("web request decoding error: {e}")))?;
+        if !status.is_success() {
+            return Err(HTTPError::Status {
+                url,
+                status,
+                body: excerpt(&response),
+            });
+        }
+        if let Some(cache) = cache {
+            cache.store(&url, etag, &response)?;
+        }
+        Ok(response)
+    }
+
+    /// posts a json body to the endpoint, responses are never cached
+    pub fn post(&self, body: &serde_json::Value) -> Result<String, HTTPError> {
+        let url = self.formulate();
+        if util::is_offline() {
+            return Err(HTTPError::Request(format!(
+                "cannot query `{url}` in offline mode"
+            )));
+        }
+        let client = Client::new();
+        let response = self.execute(|| {
+            client
+                .post(&url)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(body.to_string())
+        })?;
+        let status = response.status();
+        let response = response
             .text()
-            .map_err(|e| format!("web request decoding error: {e}"))?;
+            .map_err(|e| HTTPError::Request(format!("web request decoding error: {e}")))?;
+        if !status.is_success() {
+            return Err(HTTPError::Status {
+                url,
+                status,
+                body: excerpt(&response),
+            });
+        }
         Ok(response)
     }
+
+    /// sends the request built by make, waiting out rate limits
+    fn execute(&self, make: impl Fn() -> RequestBuilder) -> Result<Response, HTTPError> {
+        let mut attempt = 0;
+        loop {
+            let response = make()
+                .header(header::USER_AGENT, crate::user_agent())
+                .send()
+                .map_err(|e| HTTPError::Request(format!("web request failure: {e}")))?;
+            let status = response.status();
+            if (status == StatusCode::TOO_MANY_REQUESTS
+                || status == StatusCode::SERVICE_UNAVAILABLE)
+                && attempt < RATE_LIMIT_RETRIES
+            {
+                attempt += 1;
+                let wait = header_secs(&response, "retry-after")
+                    .or(header_secs(&response, "x-ratelimit-reset"))
+                    .unwrap_or(1 << attempt);
+                progress::log(&format!(
+                    "rate limited by {}, retrying in {wait}s ({attempt}/{RATE_LIMIT_RETRIES})",
+                    self.base_url
+                ));
+                thread::sleep(Duration::from_secs(wait));
+                continue;
+            }
+            // out of requests for this window, wait it out so the next query is not refused
+            if header_secs(&response, "x-ratelimit-remaining") == Some(0)
+                && let Some(reset) = header_secs(&response, "x-ratelimit-reset")
+            {
+                progress::log(&format!(
+                    "rate limit of {} reached, waiting {reset}s",
+                    self.base_url
+                ));
+                thread::sleep(Duration::from_secs(reset));
+            }
+            return Ok(response);
+        }
+    }
+}
+
+/// a failed api request
+#[derive(Debug)]
+pub enum HTTPError {
+    /// the server answered with an error status
+    Status {
+        url: String,
+        status: StatusCode,
+        body: String,
+    },
+    /// the request could not be made or its response could not be read
+    Request(String),
+}
+
+impl Display for HTTPError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HTTPError::Status { url, status, body } => {
+                write!(f, "`{url}` responded {status}")?;
+                if !body.is_empty() {
+                    write!(f, ": {body}")?;
+                }
+                Ok(())
+            }
+            HTTPError::Request(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl From<String> for HTTPError {
+    fn from(message: String) -> Self {
+        HTTPError::Request(message)
+    }
+}
+
+impl From<HTTPError> for String {
+    fn from(error: HTTPError) -> Self {
+        error.to_string()
+    }
+}
+
+fn header_secs(response: &Response, name: &str) -> Option<u64> {
+    response
+        .headers()
+        .get(name)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// the start of an error body, enough to recognise it without flooding the terminal
+fn excerpt(body: &str) -> String {
+    let body = body.trim();
+    if body.chars().count() > 200 {
+        format!("{}…", body.chars().take(200).collect::<String>())
+    } else {
+        body.to_string()
+    }
 }
@@ -1,20 +1,47 @@
-use std::io::Write;
-use std::io::stdout;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
 
 use crate::api::APIDriver;
 use crate::api::HTTPSQuery;
 use crate::api::ModResult;
-use crate::package::Derivation;
+use crate::api::ModVersion;
+use crate::api::OptionalDeps;
+use crate::package::{Derivation, Kind, Side};
+use crate::resolver::quote_version;
 use crate::store::Store;
+use crate::util::{confirm, normalize};
 use serde_json;
+use serde_json::Map;
 use serde_json::Value;
+use serde_json::json;
+use sha2::{Digest, Sha512};
 use toml::Table;
-const HOSTNAME: &str = "api.modrinth.com";
+const DEFAULT_BASE_URL: &str = "https://api.modrinth.com";
+const DEFAULT_SHADER_LOADERS: [&str; 4] = ["iris", "optifine", "canvas", "vanilla"];
+/// version types from most to least stable
+const CHANNELS: [&str; 3] = ["release", "beta", "alpha"];
+/// versions of each project looked at per round of bulk requests, newest first
+const VERSION_PAGE: usize = 50;
+/// ids sent in one bulk request, keeping the url well below what the api accepts
+const IDS_PER_REQUEST: usize = 100;
 const preamble1: &str = "api response did not contain key";
 const preamble2: &str = "api response contained key";
+/// a project id and the version it is pinned to, if any
+type Dependency = (String, Option<String>);
+
+/// dependencies of a version that are followed while resolving
+struct Dependencies {
+    required: Vec<Dependency>,
+    optional: Vec<Dependency>,
+}
 pub struct ModrinthDriver {
+    base_url: String,
     loader: String,
     versions: Vec<String>,
+    channel: String, // least stable version type installed when a newer stable one is missing
+    shader_loaders: Vec<String>,
     limit: String,
 }
 impl ModrinthDriver {
@@ -24,6 +51,13 @@ impl ModrinthDriver {
         // println!("{cfg:?}");
 
         Ok(Self {
+            base_url: match cfg.get("base_url") {
+                Some(base_url) => base_url
+                    .as_str()
+                    .ok_or("config parameter `base_url` present but not string")?
+                    .to_string(),
+                None => DEFAULT_BASE_URL.to_string(),
+            },
             loader: cfg
                 .get("loader")
                 .ok_or(format!("missing config parameter `loader`"))?
@@ -45,6 +79,33 @@ impl ModrinthDriver {
                     }
                 })
                 .collect(),
+            channel: match cfg.get("channel") {
+                Some(channel) => {
+                    let channel = channel
+                        .as_str()
+                        .ok_or("config parameter `channel` present but not string")?;
+                    if !CHANNELS.contains(&channel) {
+                        return Err(format!(
+                            "config parameter `channel` must be one of {CHANNELS:?}, not `{channel}`"
+                        ));
+                    }
+                    channel.to_string()
+                }
+                None => CHANNELS[0].to_string(),
+            },
+            shader_loaders: match cfg.get("shader_loaders") {
+                Some(loaders) => loaders
+                    .as_array()
+                    .ok_or("config parameter `shader_loaders` present but not array")?
+                    .iter()
+                    .map(|v| {
+                        v.as_str()
+                            .map(|s| s.to_string())
+                            .ok_or(format!("shader_loaders contained a non-string `{v}`"))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+                None => DEFAULT_SHADER_LOADERS.map(|s| s.to_string()).to_vec(),
+            },
             limit: cfg
                 .get("limit")
                 .ok_or(format!("missing config parameter `limit`"))?
@@ -53,72 +114,506 @@ impl ModrinthDriver {
                 .to_string(),
         })
     }
-    fn get_facets(&self) -> String {
-        let versions_str = {
-            let mut s = String::new();
-            for version in &self.versions {
-                s.push_str(&format!(",\"versions:{}\"", version));
-            }
-            s
+    fn get_facets(&self, kind: Kind) -> String {
+        let project_type = match kind {
+            Kind::Mod => "mod",
+            Kind::ResourcePack => "resourcepack",
+            Kind::Shader => "shader",
+            Kind::DataPack => "datapack",
+        };
+        let any_of = |prefix: &str, values: &[String]| {
+            values
+                .iter()
+                .map(|value| format!("\"{prefix}:{value}\""))
+                .collect::<Vec<_>>()
+                .join(",")
         };
-        let facets = format!("[[\"categories:{}\"{}]]", self.loader, versions_str);
+        // groups are and-ed, entries within a group or-ed
+        let facets = format!(
+            "[[\"project_type:{project_type}\"],[{}],[{}]]",
+            any_of("categories", &self.loaders_for(kind)),
+            any_of("versions", &self.versions)
+        );
         // println!("[MODRINTH_API_DRIVER_DEBUG] facets={facets}");
         facets
     }
 
-    fn build_derivation_for(
+    /// loaders versions of a kind of project are published for
+    fn loaders_for(&self, kind: Kind) -> Vec<String> {
+        match kind {
+            Kind::Mod => vec![self.loader.clone()],
+            Kind::ResourcePack => vec!["minecraft".to_string()],
+            Kind::Shader => self.shader_loaders.clone(),
+            Kind::DataPack => vec!["datapack".to_string()],
+        }
+    }
+
+    fn kind_of(&self, project: &Map<String, Value>) -> Result<Kind, String> {
+        match get_str(project, "project_type")? {
+            "resourcepack" => Ok(Kind::ResourcePack),
+            "shader" => Ok(Kind::Shader),
+            "datapack" => Ok(Kind::DataPack),
+            "mod" => {
+                // datapacks are listed as mods that only load as a datapack
+                let loaders: Vec<&str> = project
+                    .get("loaders")
+                    .and_then(|l| l.as_array())
+                    .map(|l| l.iter().filter_map(|l| l.as_str()).collect())
+                    .unwrap_or_default();
+                if loaders.contains(&"datapack") && !loaders.contains(&self.loader.as_str()) {
+                    Ok(Kind::DataPack)
+                } else {
+                    Ok(Kind::Mod)
+                }
+            }
+            other => Err(format!(
+                "{} is a {other}, which jade cannot install",
+                get_str(project, "slug")?
+            )),
+        }
+    }
+
+    /// resolves packages and their required dependencies one layer at a time, each layer costs
+    /// a bulk project request and usually one bulk version request
+    fn build_derivations_for(
         &self,
-        pkg_id: &str,
-        ver_id: Option<&str>,
+        packages: &[(String, Option<String>)],
         seen: &mut Vec<(String, Option<String>)>,
+        optional: OptionalDeps,
     ) -> Result<Vec<Derivation>, String> {
-        let mut formulated_derives: Vec<Derivation> = Vec::new();
-        if let Some(seen_pkg) = seen.iter().find(|v| v.0 == pkg_id) {
-            println!("{pkg_id} already installed");
-            if let Some(ver) = ver_id {
-                if let Some(installed_ver) = &seen_pkg.1 {
-                    if ver != installed_ver {
+        let mut layers: Vec<Vec<(Derivation, Vec<Dependency>)>> = Vec::new();
+        let mut names: HashMap<String, String> = HashMap::new();
+        let mut layer: Vec<(String, Option<String>)> = packages.to_vec();
+        let mut requested = true;
+        while !layer.is_empty() {
+            let projects =
+                self.get_projects(&layer.iter().map(|p| p.0.clone()).collect::<Vec<_>>())?;
+            for project in projects.values() {
+                let (id, slug) = (get_str(project, "id")?, get_str(project, "slug")?);
+                names.insert(id.to_string(), normalize(slug));
+            }
+            let mut wanted = Vec::new();
+            for (pkg_id, ver_id) in layer {
+                if let Some(seen_pkg) = seen.iter().find(|v| v.0 == pkg_id) {
+                    println!("{pkg_id} already installed");
+                    // dependencies pinned to another version are left to the resolver, but it
+                    // never sees versions asked for on the command line
+                    if requested
+                        && let Some(ver) = &ver_id
+                        && let Some(installed_ver) = &seen_pkg.1
+                        && ver != installed_ver
+                    {
                         println!(
-                            "warning: version mismatch between {pkg_id}: installed version {ver} but package requested {installed_ver}"
+                            "warning: version mismatch between {pkg_id}: installed version {installed_ver} but package requested {ver}"
                         );
                     }
+                    continue;
+                }
+                if wanted
+                    .iter()
+                    .any(|(id, _): &(String, Option<String>)| *id == pkg_id)
+                {
+                    continue;
+                }
+                seen.push((pkg_id.clone(), ver_id.clone()));
+                wanted.push((pkg_id, ver_id));
+            }
+            if wanted.is_empty() {
+                break;
+            }
+            println!("deriving {} package(s)...", wanted.len());
+            let versions = self.get_versions_for(&wanted, &projects)?;
+            let mut derived = Vec::new();
+            let mut suggested = Vec::new();
+            for (pkg_id, _) in &wanted {
+                let project = projects
+                    .get(pkg_id)
+                    .ok_or(format!("api returned no project for {pkg_id}"))?;
+                let version = versions.get(pkg_id).ok_or(format!(
+                    "no results for {} with loaders {:?} and versions {:?}",
+                    get_str(project, "slug")?,
+                    self.loaders_for(self.kind_of(project)?),
+                    self.versions
+                ))?;
+                let (derivation, dependencies) = self.derivation_from(pkg_id, project, version)?;
+                // a hand written pin may have been a version number, remember the id it resolved to
+                if let Some(entry) = seen.iter_mut().find(|entry| entry.0 == *pkg_id) {
+                    entry.1 = derivation.apiverid.clone();
+                }
+                println!("\t{} ✓", derivation.name);
+                for dependency in dependencies.optional {
+                    suggested.push((derivation.name.clone(), dependency));
+                }
+                derived.push((derivation, dependencies.required));
+            }
+            let accepted = self.choose_optional(suggested, seen, optional, &mut names)?;
+            let mut next = Vec::new();
+            let mut layer_derived = Vec::new();
+            for (derivation, required) in derived {
+                let mut depends = required.clone();
+                for (suggester, dependency) in &accepted {
+                    if *suggester == derivation.name {
+                        depends.push(dependency.clone());
+                        next.push(dependency.clone());
+                    }
                 }
+                next.extend(required);
+                layer_derived.push((derivation, depends));
+            }
+            layers.push(layer_derived);
+            layer = next;
+            requested = false;
+        }
+
+        // relations only known by project id are named once everything is resolved
+        let unnamed: Vec<String> = layers
+            .iter()
+            .flatten()
+            .flat_map(|(d, _)| d.conflicts.iter().chain(d.embedded.iter()))
+            .filter(|id| !names.contains_key(*id))
+            .cloned()
+            .collect();
+        if !unnamed.is_empty() {
+            for project in self.get_projects(&unnamed)?.values() {
+                let (id, slug) = (get_str(project, "id")?, get_str(project, "slug")?);
+                names.insert(id.to_string(), normalize(slug));
             }
-            return Ok(formulated_derives);
         }
-        print!(
-            "deriving {pkg_id}{}... ",
-            if let Some(v) = ver_id {
-                format!("/{v}")
-            } else {
-                "".to_string()
+        let name_of = |id: &String| names.get(id).cloned().unwrap_or(id.clone());
+        // dependencies on an exact version become requirements on its version number
+        let mut numbers: HashMap<String, String> = layers
+            .iter()
+            .flatten()
+            .filter_map(|(d, _)| Some((d.apiverid.clone()?, d.version.clone()?)))
+            .collect();
+        let unnumbered: Vec<String> = layers
+            .iter()
+            .flatten()
+            .flat_map(|(_, dependencies)| dependencies.iter().filter_map(|d| d.1.clone()))
+            .filter(|id| !numbers.contains_key(id))
+            .collect();
+        if !unnumbered.is_empty() {
+            for version in self.get_versions(&unnumbered)? {
+                numbers.insert(
+                    get_str(&version, "id")?.to_string(),
+                    get_str(&version, "version_number")?.to_string(),
+                );
             }
-        );
-        stdout().flush();
-        let versions_str = {
-            let mut s = String::new();
-            for (i, version) in self.versions.iter().enumerate() {
-                if i != 0 {
-                    s.push(',');
+        }
+        let requirement_of =
+            |(id, version): &Dependency| match version.as_ref().and_then(|v| numbers.get(v)) {
+                Some(number) => format!("{} ={}", name_of(id), quote_version(number)),
+                None => name_of(id),
+            };
+        let embedded: Vec<String> = layers
+            .iter()
+            .flatten()
+            .flat_map(|(d, _)| d.embedded.clone())
+            .collect();
+
+        // dependencies come before the packages needing them
+        let mut formulated_derives = Vec::new();
+        for layer in layers.into_iter().rev() {
+            for (mut derivation, dependencies) in layer {
+                if derivation
+                    .apipkgid
+                    .as_ref()
+                    .is_some_and(|id| embedded.contains(id))
+                {
+                    println!(
+                        "{} is embedded in another package, not installing it separately",
+                        derivation.name
+                    );
+                    continue;
                 }
-                s.push_str(&format!("\"{}\"", version));
+                derivation.depends = dependencies.iter().map(requirement_of).collect();
+                derivation.conflicts = derivation.conflicts.iter().map(name_of).collect();
+                derivation.embedded = derivation.embedded.iter().map(name_of).collect();
+                formulated_derives.push(derivation);
             }
-            s
+        }
+        Ok(formulated_derives)
+    }
+
+    /// decides which suggested optional dependencies, as (suggested by, dependency), to install
+    fn choose_optional(
+        &self,
+        suggested: Vec<(String, Dependency)>,
+        seen: &[(String, Option<String>)],
+        optional: OptionalDeps,
+        names: &mut HashMap<String, String>,
+    ) -> Result<Vec<(String, Dependency)>, String> {
+        let suggested: Vec<_> = suggested
+            .into_iter()
+            .filter(|(_, dependency)| !seen.iter().any(|s| s.0 == dependency.0))
+            .collect();
+        if suggested.is_empty() || optional == OptionalDeps::Skip {
+            return Ok(Vec::new());
+        }
+        let projects = self.get_projects(
+            &suggested
+                .iter()
+                .map(|(_, d)| d.0.clone())
+                .collect::<Vec<_>>(),
+        )?;
+        for project in projects.values() {
+            let (id, slug) = (get_str(project, "id")?, get_str(project, "slug")?);
+            names.insert(id.to_string(), normalize(slug));
+        }
+        let mut accepted: Vec<(String, Dependency)> = Vec::new();
+        for (suggester, dependency) in suggested {
+            if accepted.iter().any(|(_, d)| d.0 == dependency.0) {
+                continue;
+            }
+            let install = match optional {
+                OptionalDeps::All => true,
+                OptionalDeps::Skip => false,
+                OptionalDeps::Ask => {
+                    let description = projects
+                        .get(&dependency.0)
+                        .and_then(|p| p.get("description"))
+                        .and_then(|d| d.as_str())
+                        .unwrap_or("");
+                    let name = names.get(&dependency.0).unwrap_or(&dependency.0);
+                    confirm(
+                        &format!(
+                            "{suggester} optionally depends on {name} ({description}), install it?"
+                        ),
+                        false,
+                    )?
+                }
+            };
+            if install {
+                accepted.push((suggester, dependency));
+            }
+        }
+        Ok(accepted)
+    }
+
+    /// project id -> project, in as few requests as the url length allows
+    fn get_projects(&self, ids: &[String]) -> Result<HashMap<String, Map<String, Value>>, String> {
+        let mut projects = HashMap::new();
+        for batch in ids.chunks(IDS_PER_REQUEST) {
+            let batch =
+                serde_json::to_string(batch).map_err(|e| format!("failed to encode ids: {e}"))?;
+            let response = HTTPSQuery::new(&self.base_url, "v2/projects")
+                .add_parameter("ids", &batch)?
+                .send()?
+                .parse::<Value>()
+                .map_err(|e| format!("could not parse api json response {e}"))?;
+            for project in as_objects(&response, "projects")? {
+                projects.insert(get_str(&project, "id")?.to_string(), project);
+            }
+        }
+        Ok(projects)
+    }
+
+    /// the versions with ids, in as few requests as the url length allows
+    fn get_versions(&self, ids: &[String]) -> Result<Vec<Map<String, Value>>, String> {
+        let mut versions = Vec::new();
+        for batch in ids.chunks(IDS_PER_REQUEST) {
+            let batch =
+                serde_json::to_string(batch).map_err(|e| format!("failed to encode ids: {e}"))?;
+            let response = HTTPSQuery::new(&self.base_url, "v2/versions")
+                .add_parameter("ids", &batch)?
+                .send()?
+                .parse::<Value>()
+                .map_err(|e| format!("could not parse api response json {e}"))?;
+            versions.extend(as_objects(&response, "versions")?);
+        }
+        Ok(versions)
+    }
+
+    /// project id -> version to install. pinned versions are fetched in bulk, the rest are picked
+    /// from the version lists of their projects, fetched in bulk a page at a time from the newest
+    fn get_versions_for(
+        &self,
+        packages: &[(String, Option<String>)],
+        projects: &HashMap<String, Map<String, Value>>,
+    ) -> Result<HashMap<String, Map<String, Value>>, String> {
+        let mut versions = HashMap::new();
+        let pinned: Vec<String> = packages
+            .iter()
+            .filter_map(|p| p.1.clone())
+            .filter(|spec| is_version_id(spec))
+            .collect();
+        if !pinned.is_empty() {
+            for version in self.get_versions(&pinned)? {
+                versions.insert(get_str(&version, "project_id")?.to_string(), version);
+            }
+        }
+        // pins given by hand may be version numbers rather than ids, those are looked up one by one
+        for (pkg_id, spec) in packages {
+            let Some(spec) = spec else {
+                continue;
+            };
+            if versions.contains_key(pkg_id) {
+                continue;
+            }
+            let response = HTTPSQuery::new(
+                &self.base_url,
+                &format!("v2/project/{pkg_id}/version/{spec}"),
+            )
+            .send()
+            .map_err(|e| format!("no version {spec} of {pkg_id}: {e}"))?
+            .parse::<Value>()
+            .map_err(|e| format!("could not parse api response json {e}"))?;
+            let version = response
+                .as_object()
+                .ok_or("api response was not an object")?
+                .to_owned();
+            versions.insert(pkg_id.clone(), version);
+        }
+
+        // version lists are oldest first, so pages are taken from their end
+        let mut remaining: HashMap<&String, (Kind, Vec<String>)> = HashMap::new();
+        for (pkg_id, spec) in packages {
+            if spec.is_some() {
+                continue;
+            }
+            let project = projects
+                .get(pkg_id)
+                .ok_or(format!("api returned no project for {pkg_id}"))?;
+            let ids = project
+                .get("versions")
+                .and_then(|v| v.as_array())
+                .map(|v| {
+                    v.iter()
+                        .filter_map(|id| id.as_str().map(|id| id.to_string()))
+                        .collect()
+                })
+                .unwrap_or_default();
+            remaining.insert(pkg_id, (self.kind_of(project)?, ids));
+        }
+        // the newest compatible version of projects without one on an accepted channel
+        let mut newest: HashMap<&String, Map<String, Value>> = HashMap::new();
+        while !remaining.is_empty() {
+            let mut page = Vec::new();
+            for (_, ids) in remaining.values_mut() {
+                page.extend(ids.split_off(ids.len().saturating_sub(VERSION_PAGE)));
+            }
+            let mut listed: HashMap<String, Vec<Map<String, Value>>> = HashMap::new();
+            if !page.is_empty() {
+                for version in self.get_versions(&page)? {
+                    listed
+                        .entry(get_str(&version, "project_id")?.to_string())
+                        .or_default()
+                        .push(version);
+                }
+            }
+            let mut done = Vec::new();
+            for (pkg_id, (kind, ids)) in &remaining {
+                let mut compatible: Vec<Map<String, Value>> = listed
+                    .remove(*pkg_id)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|v| self.is_compatible(v, *kind))
+                    .collect();
+                compatible.sort_by(|a, b| {
+                    let published = |v: &Map<String, Value>| {
+                        v.get("date_published")
+                            .and_then(|d| d.as_str())
+                            .map(|d| d.to_string())
+                    };
+                    published(b).cmp(&published(a))
+                });
+                if let Some(version) = compatible
+                    .iter()
+                    .find(|v| self.accepts_channel(v.get("version_type").and_then(|t| t.as_str())))
+                {
+                    versions.insert((*pkg_id).clone(), version.clone());
+                    done.push(*pkg_id);
+                    continue;
+                }
+                if let Some(version) = compatible.into_iter().next() {
+                    newest.entry(*pkg_id).or_insert(version);
+                }
+                if ids.is_empty() {
+                    done.push(*pkg_id);
+                }
+            }
+            for pkg_id in done {
+                remaining.remove(pkg_id);
+            }
+        }
+        for (pkg_id, version) in newest {
+            if versions.contains_key(pkg_id) {
+                continue;
+            }
+            println!(
+                "warning: {pkg_id} has no {} versions, using {} {}",
+                self.channel,
+                version
+                    .get("version_type")
+                    .and_then(|t| t.as_str())
+                    .unwrap_or("unknown"),
+                get_str(&version, "version_number")?
+            );
+            versions.insert(pkg_id.clone(), version);
+        }
+        Ok(versions)
+    }
+
+    /// whether a version is published for the pack's game versions and the loaders of kind
+    fn is_compatible(&self, version: &Map<String, Value>, kind: Kind) -> bool {
+        let any_of = |key: &str, wanted: &[String]| {
+            version
+                .get(key)
+                .and_then(|v| v.as_array())
+                .is_some_and(|v| {
+                    v.iter()
+                        .any(|v| v.as_str().is_some_and(|v| wanted.iter().any(|w| w == v)))
+                })
         };
-        // let facets = format!("[[\"loader:{}\"{}]]", self.loader, versions_str);
-        let base_package = HTTPSQuery::new(HOSTNAME, &format!("v2/project/{pkg_id}"))
+        any_of("loaders", &self.loaders_for(kind)) && any_of("game_versions", &self.versions)
+    }
+
+    /// versions matching the pack's loader and game versions, newest first
+    fn compatible_versions(
+        &self,
+        pkg_id: &str,
+        kind: Kind,
+    ) -> Result<Vec<Map<String, Value>>, String> {
+        let loaders = serde_json::to_string(&self.loaders_for(kind))
+            .map_err(|e| format!("failed to encode loaders: {e}"))?;
+        let response = HTTPSQuery::new(&self.base_url, &format!("v2/project/{pkg_id}/version"))
+            .add_parameter("loaders", &loaders)?
+            .add_parameter("game_versions", &self.versions_array())?
             .send()?
             .parse::<Value>()
-            .map_err(|e| format!("could not parse api json response {e}"))?
-            .as_object()
-            .ok_or(format!("api response was not an object"))?
-            .to_owned();
-        let name = base_package
-            .get("slug")
-            .ok_or(format!("{preamble1} `slug`"))?
-            .as_str()
-            .ok_or(format!("{preamble2} `slug` but was not a string"))?;
-        let mut categories = base_package
+            .map_err(|e| format!("could not parse api response json {e}"))?;
+        as_objects(&response, "versions")
+    }
+
+    /// whether a version type is at least as stable as the configured channel
+    fn accepts_channel(&self, version_type: Option<&str>) -> bool {
+        let rank = |channel: &str| CHANNELS.iter().position(|c| *c == channel);
+        match (version_type.and_then(rank), rank(&self.channel)) {
+            (Some(version), Some(wanted)) => version <= wanted,
+            _ => false,
+        }
+    }
+
+    fn versions_array(&self) -> String {
+        let mut s = String::new();
+        for (i, version) in self.versions.iter().enumerate() {
+            if i != 0 {
+                s.push(',');
+            }
+            s.push_str(&format!("\"{}\"", version));
+        }
+        format!("[{s}]")
+    }
+
+    /// builds the derivation for a version of a project, along with its required dependencies
+    fn derivation_from(
+        &self,
+        pkg_id: &str,
+        project: &Map<String, Value>,
+        version: &Map<String, Value>,
+    ) -> Result<(Derivation, Dependencies), String> {
+        let name = get_str(project, "slug")?;
+        let categories = project
             .get("categories")
             .ok_or(format!("{preamble1} `categories`"))?
             .as_array()
@@ -133,72 +628,19 @@ impl ModrinthDriver {
                     .map(|s| s.to_string())
             })
             .collect::<Result<Vec<_>, _>>()?;
-        print!("{name} ");
         let side = {
-            let client_side = base_package
-                .get("client_side")
-                .ok_or(format!("{preamble1} `client_side`"))?
-                .as_str()
-                .ok_or(format!("{preamble2} `client_side` but was not a string"))?
-                .to_string();
-            let server_side = base_package
-                .get("server_side")
-                .ok_or(format!("{preamble1} `server_side`"))?
-                .as_str()
-                .ok_or(format!("{preamble2} `server_side` but was not a string"))?
-                .to_string();
-            let client = client_side != "unsupported" || client_side != "unknown";
-            let server = server_side != "unsupported" || server_side != "unknown";
+            let client_side = get_str(project, "client_side")?;
+            let server_side = get_str(project, "server_side")?;
+            let client = client_side == "required" || client_side == "optional";
+            let server = server_side == "required" || server_side == "optional";
             match (client, server) {
-                (true, true) => "both".to_string(),
-                (true, false) => "client".to_string(),
-                (false, true) => "server".to_string(),
-                (false, false) => unreachable!(),
+                (true, false) => Side::Client,
+                (false, true) => Side::Server,
+                // projects that do not say where they run are assumed to run everywhere
+                _ => Side::Both,
             }
         };
-        categories.push(side);
-        stdout().flush();
-        let version = if let Some(specific) = ver_id {
-            let url = HTTPSQuery::new(HOSTNAME, &format!("v2/project/{pkg_id}/version/{specific}"))
-                .add_parameter("loaders", &format!("[\"{}\"]", self.loader))?
-                .add_parameter("game_versions", &format!("[{}]", versions_str))?;
-            let response = url
-                .send()?
-                .parse::<Value>()
-                .map_err(|e| format!("could not parse api response json {e}"))?;
-
-            response
-                .as_object()
-                .ok_or(format!("api response was not an object"))?
-                .to_owned()
-        } else {
-            let url = HTTPSQuery::new(HOSTNAME, &format!("v2/project/{pkg_id}/version"))
-                .add_parameter("loaders", &format!("[\"{}\"]", self.loader))?
-                .add_parameter("game_versions", &format!("[{}]", versions_str))?;
-            let response = url
-                .send()?
-                .parse::<Value>()
-                .map_err(|e| format!("could not parse api response json {e}"))?;
-
-            // later maybe make this user selected
-            response
-                .as_array()
-                .ok_or(format!("api response was not an array"))?
-                .get(0)
-                .ok_or(format!(
-                    "no results for {name} with loader {} and versions {:?}",
-                    self.loader, self.versions
-                ))?
-                .as_object()
-                .ok_or(format!("api response was not an object"))?
-                .to_owned()
-        };
-        println!("✓");
-        let version_id = version
-            .get("id")
-            .ok_or(format!("{preamble1} `id`"))?
-            .as_str()
-            .ok_or(format!("{preamble2} `files` but was not a string"))?;
+        let version_id = get_str(version, "id")?;
         let files = version
             .get("files")
             .ok_or(format!("{preamble1} `files`"))?
@@ -212,17 +654,13 @@ impl ModrinthDriver {
                 })
             })
             .collect::<Result<Vec<_>, _>>()?;
-        let file = files[0].clone(); // might change this to user selected
-        let url = file
-            .get("url")
-            .ok_or(format!("{preamble1} `url`"))?
-            .as_str()
-            .ok_or(format!("{preamble2} `url` but was not a string"))?;
-        let file_name = file
-            .get("filename")
-            .ok_or(format!("{preamble1} `filename`"))?
-            .as_str()
-            .ok_or(format!("{preamble2} `filename` but was not a string"))?;
+        let file = files
+            .iter()
+            .find(|f| f.get("primary").and_then(|p| p.as_bool()) == Some(true))
+            .or(files.first())
+            .ok_or(format!("version {version_id} of {name} has no files"))?;
+        let url = get_str(file, "url")?;
+        let file_name = get_str(file, "filename")?;
         let hash = Some(
             file.get("hashes")
                 .ok_or(format!("{preamble1} `hashes`"))?
@@ -240,69 +678,108 @@ impl ModrinthDriver {
             .get("dependencies")
             .ok_or(format!("{preamble1} `dependencies`"))?
             .as_array()
-            .ok_or(format!("{preamble2}"))?;
+            .ok_or(format!("{preamble2} `dependencies` but was not an array"))?;
 
-        let mut depends: Vec<String> = Vec::new();
+        let mut dependencies = Dependencies {
+            required: Vec::new(),
+            optional: Vec::new(),
+        };
+        let mut conflicts = Vec::new();
+        let mut embedded = Vec::new();
         for depend in depend_ids {
-            let required = match depend
+            let dependency_type = depend
                 .get("dependency_type")
                 .ok_or(format!("{preamble1} `dependency_type`"))?
                 .as_str()
                 .ok_or(format!(
                     "{preamble2} `dependency_type` but was not a string"
-                ))? {
-                "required" => true,
-                _ => false,
-            };
-            if required {
-                let project_id = depend
-                    .get("project_id")
-                    .ok_or(format!("{preamble1} `project_id`"))?
-                    .as_str()
-                    .ok_or(format!("{preamble2} `project_id` but was not a string"))?;
-                let version_id = depend
-                    .get("version_id")
-                    .ok_or(format!("{preamble1} `version_id`"))?
-                    .as_str();
-                // .map(|s| s.to_string());
-                // .ok_or(format!("{preamble2} `version_id` but was not a string"))?;
-                let derived = self.build_derivation_for(project_id, version_id, seen)?;
-
-                for derive in &derived {
-                    depends.push(derive.name.clone());
+                ))?;
+            let project_id = depend.get("project_id").and_then(|p| p.as_str());
+            let Some(project_id) = project_id else {
+                // relations by file name alone cannot be resolved, only required ones matter
+                if dependency_type == "required" {
+                    return Err(format!(
+                        "{preamble2} `project_id` but was not a string, cannot resolve required dependency of {name}"
+                    ));
                 }
-                formulated_derives.extend(derived);
+                continue;
+            };
+            let version_id = depend.get("version_id").and_then(|v| v.as_str());
+            let dependency = (project_id.to_string(), version_id.map(|v| v.to_string()));
+            match dependency_type {
+                "required" => dependencies.required.push(dependency),
+                "optional" => dependencies.optional.push(dependency),
+                "incompatible" => conflicts.push(dependency.0),
+                "embedded" => embedded.push(dependency.0),
+                _ => (),
             }
         }
-        // let hash = hash.map(|h| nix_base32::to_nix_base32(&h.into_bytes()));
-        // println!("HASH: {hash:?}");
-        let master_derive = Derivation::new(
+        let mut derivation = Derivation::new(
             url,
             name,
             file_name,
             false,
             None,
             hash,
-            depends,
+            Vec::new(),
             categories,
             Some(pkg_id.to_string()),
             Some(version_id.to_string()),
         );
-        formulated_derives.push(master_derive);
-        Ok(formulated_derives)
+        // named by the caller once every project id is known
+        derivation.version = Some(get_str(version, "version_number")?.to_string());
+        derivation.side = side;
+        derivation.kind = self.kind_of(project)?;
+        derivation.conflicts = conflicts;
+        derivation.embedded = embedded;
+        Ok((derivation, dependencies))
     }
 }
 
+/// modrinth ids are 8 base62 characters, other pins written by hand are version numbers
+fn is_version_id(spec: &str) -> bool {
+    spec.len() == 8 && spec.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn get_str<'a>(object: &'a Map<String, Value>, key: &str) -> Result<&'a str, String> {
+    object
+        .get(key)
+        .ok_or(format!("{preamble1} `{key}`"))?
+        .as_str()
+        .ok_or(format!("{preamble2} `{key}` but was not a string"))
+}
+
+fn as_objects(response: &Value, what: &str) -> Result<Vec<Map<String, Value>>, String> {
+    response
+        .as_array()
+        .ok_or(format!("api response for {what} was not an array"))?
+        .iter()
+        .map(|v| {
+            v.as_object().cloned().ok_or(format!(
+                "api response for {what} contained a non-object: {v}"
+            ))
+        })
+        .collect()
+}
+
+fn sha512_file(path: &str) -> Result<String, String> {
+    let mut file =
+        File::open(path).map_err(|e| format!("failed to open `{path}` for hashing: {e}"))?;
+    let mut hasher = Sha512::new();
+    io::copy(&mut file, &mut hasher).map_err(|e| format!("failed to hash `{path}`: {e}"))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
 impl APIDriver for ModrinthDriver {
     // fn configure(&mut self, cfg: &toml::Table) -> Result<(), String> {
     //     todo!()
     // }
 
-    fn search(&self, query: &str) -> Result<Vec<crate::api::ModResult>, String> {
-        println!("searching `{query}`...");
-        let url = HTTPSQuery::new("api.modrinth.com", "v2/search")
+    fn search(&self, query: &str, kind: Kind) -> Result<Vec<crate::api::ModResult>, String> {
+        println!("searching {kind}s for `{query}`...");
+        let url = HTTPSQuery::new(&self.base_url, "v2/search")
             .add_parameter("query", query)?
-            .add_parameter("facets", &self.get_facets())?
+            .add_parameter("facets", &self.get_facets(kind))?
             .add_parameter("limit", &self.limit)?;
         let response: Value = url
             .send()?
@@ -379,15 +856,17 @@ impl APIDriver for ModrinthDriver {
 
     fn get_derivations_for(
         &self,
-        pkg_id: &str,
+        packages: &[(String, Option<String>)],
         seen: &mut Vec<(String, Option<String>)>,
+        optional: OptionalDeps,
         hash: bool,
         store: &Store,
     ) -> Result<Vec<crate::package::Derivation>, String> {
-        let mut derivations = self.build_derivation_for(pkg_id, None, seen)?;
+        let mut derivations = self.build_derivations_for(packages, seen, optional)?;
         if hash {
             for derive in &mut derivations {
                 let file_path = derive.download(
+                    &store.downloader,
                     &store.temp,
                     derive.hash.clone(),
                     Some("sha512".to_string()),
@@ -399,6 +878,139 @@ impl APIDriver for ModrinthDriver {
         }
         Ok(derivations)
     }
+
+    fn version_numbers(&self, version_ids: &[String]) -> Result<HashMap<String, String>, String> {
+        self.get_versions(version_ids)?
+            .iter()
+            .map(|version| {
+                Ok((
+                    get_str(version, "id")?.to_string(),
+                    get_str(version, "version_number")?.to_string(),
+                ))
+            })
+            .collect()
+    }
+
+    fn list_versions(&self, pkg_id: &str) -> Result<Vec<ModVersion>, String> {
+        let project = self
+            .get_projects(&[pkg_id.to_string()])?
+            .remove(pkg_id)
+            .ok_or(format!("api returned no project for {pkg_id}"))?;
+        self.compatible_versions(pkg_id, self.kind_of(&project)?)?
+            .iter()
+            .map(|version| {
+                Ok(ModVersion {
+                    id: get_str(version, "id")?.to_string(),
+                    number: get_str(version, "version_number")?.to_string(),
+                    channel: get_str(version, "version_type")?.to_string(),
+                    published: get_str(version, "date_published")?.to_string(),
+                    game_versions: version
+                        .get("game_versions")
+                        .and_then(|v| v.as_array())
+                        .map(|v| {
+                            v.iter()
+                                .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+
+    fn find_updates(
+        &self,
+        derivations: &[Derivation],
+        store: &Store,
+    ) -> Result<Vec<(String, String)>, String> {
+        // modrinth identifies files by hash, the store holds every realized artifact
+        let mut by_hash: HashMap<String, &Derivation> = HashMap::new();
+        let mut unhashed = Vec::new();
+        for derivation in derivations {
+            if derivation.apipkgid.is_none() {
+                continue;
+            }
+            match store.is_package_in_store(derivation) {
+                Some(path) if Path::new(&path.get_artifact()).is_file() => {
+                    by_hash.insert(sha512_file(&path.get_artifact())?, derivation);
+                }
+                _ => unhashed.push(derivation),
+            }
+        }
+        // the rest are looked up by their recorded version instead
+        let version_ids: Vec<String> = unhashed.iter().filter_map(|d| d.apiverid.clone()).collect();
+        if !version_ids.is_empty() {
+            for version in self.get_versions(&version_ids)? {
+                let Some(derivation) = unhashed
+                    .iter()
+                    .find(|d| d.apiverid.as_deref() == Some(get_str(&version, "id").unwrap_or("")))
+                else {
+                    continue;
+                };
+                let file = version
+                    .get("files")
+                    .and_then(|files| files.as_array())
+                    .and_then(|files| {
+                        files.iter().find(|f| {
+                            f.get("filename").and_then(|n| n.as_str())
+                                == Some(derivation.file_name.as_str())
+                        })
+                    });
+                if let Some(sha512) = file
+                    .and_then(|f| f.get("hashes"))
+                    .and_then(|h| h.get("sha512"))
+                    .and_then(|h| h.as_str())
+                {
+                    by_hash.insert(sha512.to_string(), derivation);
+                }
+            }
+        }
+        // one request per kind of derivation in the pack, as each is published for other loaders
+        let mut latest = Map::new();
+        for kind in Kind::ALL {
+            let hashes: Vec<&String> = by_hash
+                .iter()
+                .filter(|(_, d)| d.kind == kind)
+                .map(|(hash, _)| hash)
+                .collect();
+            if hashes.is_empty() {
+                continue;
+            }
+            let response = HTTPSQuery::new(&self.base_url, "v2/version_files/update")
+                .post(&json!({
+                    "hashes": hashes,
+                    "algorithm": "sha512",
+                    "loaders": self.loaders_for(kind),
+                    "game_versions": self.versions,
+                }))?
+                .parse::<Value>()
+                .map_err(|e| format!("could not parse api response json {e}"))?;
+            latest.extend(
+                response
+                    .as_object()
+                    .ok_or("api response was not an object")?
+                    .clone(),
+            );
+        }
+        let mut updates = Vec::new();
+        for (hash, version) in &latest {
+            let (Some(derivation), Some(version)) = (by_hash.get(hash), version.as_object()) else {
+                continue;
+            };
+            let version_id = get_str(version, "id")?;
+            // the endpoint ignores channels, an unstable newest version is not offered
+            if !self.accepts_channel(version.get("version_type").and_then(|t| t.as_str())) {
+                continue;
+            }
+            if derivation.apiverid.as_deref() != Some(version_id) {
+                updates.push((
+                    get_str(version, "project_id")?.to_string(),
+                    version_id.to_string(),
+                ));
+            }
+        }
+        Ok(updates)
+    }
 }
 
 // macro_rules! extract_key {
@@ -0,0 +1,281 @@
+// portable bundles of a pack and every store path it needs, for machines without network access
+//
+// layout of a closure archive:
+//   closure.json          signatures and content hashes of the bundled store paths
+//   manifest.jade.toml    the pack manifest
+//   derives/<name>.jade.toml
+//   store/<hash>-<name>/artifact
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    path::Path,
+};
+
+use chrono::Utc;
+use colorize::AnsiColor;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    MANIFEST,
+    manifest::Manifest,
+    package::{Derivation, load_derivations_from_directory},
+    progress,
+    store::{Integrity, Store, StorePath},
+    util::hash_directory,
+};
+
+const CLOSURE_INDEX: &str = "closure.json";
+
+#[derive(Serialize, Deserialize)]
+struct ClosureIndex {
+    pack: String,
+    /// signature -> content hash for directory artifacts
+    paths: BTreeMap<String, Option<String>>,
+}
+
+/// bundles the store paths of derivations, the merged manifest and a snapshot of the derivations
+pub fn export_closure(
+    store: &Store,
+    manifest: &Manifest,
+    derivations: &[Derivation],
+    output: &str,
+) -> Result<(), String> {
+    let file =
+        File::create(output).map_err(|e| format!("failed to create closure `{output}`: {e}"))?;
+    let mut archive = tar::Builder::new(file);
+    archive.follow_symlinks(false);
+    let tar_error = |e: std::io::Error| format!("failed to write closure `{output}`: {e}");
+
+    // derivations of included packs are bundled, so the restored pack carries the merged manifest
+    // without includes, and its derivations always live in derives/
+    let mut contents = toml::Table::try_from(manifest).map_err(|e| {
+        format!(
+            "failed to serialize manifest `{}`: {e}",
+            manifest.backing_file
+        )
+    })?;
+    contents.remove("include");
+    if let Some(toml::Value::Table(main)) = contents.get_mut("main") {
+        main.remove("derives");
+    }
+    let contents = toml::to_string(&contents).map_err(|e| {
+        format!(
+            "failed to serialize manifest `{}`: {e}",
+            manifest.backing_file
+        )
+    })?;
+    append_bytes(&mut archive, MANIFEST, contents.as_bytes()).map_err(tar_error)?;
+    let mut index = ClosureIndex {
+        pack: manifest.main.name.clone(),
+        paths: BTreeMap::new(),
+    };
+    for derivation in derivations {
+        let store_path = store.is_package_in_store(derivation).ok_or(format!(
+            "{} is not in the store, compose the pack before exporting it",
+            derivation.name
+        ))?;
+        let signature = store_path.signature();
+        progress::log(&format!("adding {signature}"));
+        let serialized = toml::to_string(derivation).map_err(|e| {
+            format!(
+                "failed to serialize derivation for {}: {e}",
+                derivation.name
+            )
+        })?;
+        append_bytes(
+            &mut archive,
+            &format!("derives/{}.jade.toml", derivation.name),
+            serialized.as_bytes(),
+        )
+        .map_err(tar_error)?;
+        let artifact = store_path.get_artifact();
+        let name = format!("store/{signature}/artifact");
+        if Path::new(&artifact).is_dir() {
+            archive.append_dir_all(&name, &artifact)
+        } else {
+            archive.append_path_with_name(&artifact, &name)
+        }
+        .map_err(tar_error)?;
+        let content_hash = store_path.content_hash();
+        if let Some(content_hash) = &content_hash {
+            append_bytes(
+                &mut archive,
+                &format!("store/{signature}/content-hash"),
+                content_hash.as_bytes(),
+            )
+            .map_err(tar_error)?;
+        }
+        index.paths.insert(signature, content_hash);
+    }
+    let serialized = serde_json::to_string_pretty(&index)
+        .map_err(|e| format!("failed to serialize closure index: {e}"))?;
+    append_bytes(&mut archive, CLOSURE_INDEX, serialized.as_bytes()).map_err(tar_error)?;
+    archive.finish().map_err(tar_error)?;
+    println!(
+        "exported {} store paths of {} to {output}",
+        index.paths.len(),
+        index.pack
+    );
+    Ok(())
+}
+
+/// verifies and adds every store path of a closure to the store, optionally restoring the pack
+/// definition into pack_dir, with strict only trees vouched for outside the closure are added
+pub fn import_closure(
+    store: &Store,
+    closure: &str,
+    pack_dir: Option<&str>,
+    strict: bool,
+) -> Result<(), String> {
+    let staging = format!(
+        "{}/import-{}",
+        store.temp,
+        Utc::now().format("%Y%m%d%H%M%S%f")
+    );
+    let file =
+        File::open(closure).map_err(|e| format!("failed to open closure `{closure}`: {e}"))?;
+    tar::Archive::new(file)
+        .unpack(&staging)
+        .map_err(|e| format!("failed to unpack closure `{closure}`: {e}"))?;
+    let result = import_unpacked(store, &staging, pack_dir, strict);
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+fn import_unpacked(
+    store: &Store,
+    staging: &str,
+    pack_dir: Option<&str>,
+    strict: bool,
+) -> Result<(), String> {
+    let index_path = format!("{staging}/{CLOSURE_INDEX}");
+    let index: ClosureIndex = serde_json::from_str(
+        &fs::read_to_string(&index_path)
+            .map_err(|e| format!("closure has no readable {CLOSURE_INDEX}: {e}"))?,
+    )
+    .map_err(|e| format!("failed to parse {CLOSURE_INDEX}: {e}"))?;
+    let derivations = load_derivations_from_directory(Path::new(&format!("{staging}/derives")))?;
+
+    // trees are checked against the content hashes in the closure index, the same trust given
+    // to the archive hashes of file artifacts, unless this store or the pack already at pack_dir
+    // pinned them, with strict those pins are required
+    let existing_pack = pack_dir.filter(|dir| Path::new(&format!("{dir}/{MANIFEST}")).exists());
+    let pinned = match existing_pack {
+        Some(dir) => load_derivations_from_directory(Path::new(&format!("{dir}/derives")))?,
+        None => Vec::new(),
+    };
+    let db = store.load_db()?;
+    let mut anchors = BTreeMap::new();
+    let mut skipped = Vec::new();
+
+    // verify everything before anything touches the store
+    for signature in index.paths.keys() {
+        let (hash, name) = signature
+            .split_once('-')
+            .ok_or(format!("malformed store path `{signature}` in closure"))?;
+        let bundled = StorePath::new(&format!("{staging}/store/{signature}"), name, hash);
+        let artifact = bundled.get_artifact();
+        if Path::new(&artifact).is_dir() {
+            let anchor = pinned
+                .iter()
+                .find(|d| d.hash.is_some() && d.generate_hash_signature() == *signature)
+                .and_then(|d| d.content_hash.clone())
+                .or_else(|| {
+                    db.entries
+                        .get(signature)
+                        .and_then(|e| e.content_hash.clone())
+                });
+            let bundled_hash = index.paths.get(signature).cloned().flatten();
+            let anchor = match (anchor, bundled_hash) {
+                (Some(anchor), _) => anchor,
+                (None, Some(bundled_hash)) if !strict => bundled_hash,
+                (None, bundled_hash) => {
+                    println!(
+                        "{} no content hash for {signature} is known{}, skipping it",
+                        "warning:".yellow(),
+                        if bundled_hash.is_some() {
+                            " outside the closure"
+                        } else {
+                            ""
+                        }
+                    );
+                    skipped.push(signature.clone());
+                    continue;
+                }
+            };
+            let actual = hash_directory(Path::new(&artifact))?;
+            if actual != anchor {
+                return Err(format!(
+                    "{signature} is corrupt (hashes to {actual}, expected {anchor})"
+                ));
+            }
+            anchors.insert(signature.clone(), anchor);
+            continue;
+        }
+        match bundled.verify()? {
+            Integrity::Valid => (),
+            Integrity::Unverifiable(reason) => {
+                return Err(format!("cannot verify {signature}: {reason}"));
+            }
+            Integrity::Corrupt { actual } => {
+                return Err(format!("{signature} is corrupt (hashes to {actual})"));
+            }
+            Integrity::Missing => return Err(format!("{signature} is missing from the closure")),
+        }
+    }
+
+    let mut added = 0;
+    for signature in index.paths.keys() {
+        if skipped.contains(signature) {
+            continue;
+        }
+        let derivation = derivations
+            .iter()
+            .find(|d| d.hash.is_some() && d.generate_hash_signature() == *signature)
+            .ok_or(format!("closure has no derivation producing {signature}"))?;
+        if store.is_package_in_store(derivation).is_some() {
+            progress::log(&format!("{signature} already in store"));
+            continue;
+        }
+        derivation.install_to_store(store, &format!("{staging}/store/{signature}/artifact"))?;
+        added += 1;
+    }
+    println!(
+        "imported {added} new store paths ({} already present, {} skipped) from {}",
+        index.paths.len() - added - skipped.len(),
+        skipped.len(),
+        index.pack
+    );
+
+    if let Some(pack_dir) = pack_dir {
+        let manifest = format!("{pack_dir}/{MANIFEST}");
+        if existing_pack.is_some() {
+            println!("`{manifest}` already exists, not restoring pack");
+            return Ok(());
+        }
+        fs::create_dir_all(format!("{pack_dir}/derives"))
+            .map_err(|e| format!("failed to create `{pack_dir}/derives`: {e}"))?;
+        fs::copy(format!("{staging}/{MANIFEST}"), &manifest)
+            .map_err(|e| format!("failed to restore manifest to `{manifest}`: {e}"))?;
+        for mut derivation in derivations {
+            // only pins that were verified here are carried over
+            derivation.content_hash = derivation
+                .hash
+                .as_ref()
+                .and_then(|_| anchors.get(&derivation.generate_hash_signature()).cloned());
+            derivation.backing_file = format!("{pack_dir}/derives/{}.jade.toml", derivation.name);
+            derivation.write_back()?;
+        }
+        println!("restored {} to {pack_dir}", index.pack);
+    }
+    Ok(())
+}
+
+fn append_bytes(archive: &mut tar::Builder<File>, path: &str, bytes: &[u8]) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(bytes.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    header.set_cksum();
+    archive.append_data(&mut header, path, bytes)
+}
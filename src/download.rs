@@ -0,0 +1,303 @@
+// shared download engine: bounded concurrency, per-host limits, retries and resumable transfers
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::Path,
+    sync::{Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use reqwest::{StatusCode, blocking::Client, header};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::{
+    progress::{self, FileProgress, progress},
+    util::hash_stream,
+};
+
+pub const DEFAULT_JOBS: usize = 8;
+pub const DEFAULT_CONNECTIONS_PER_HOST: usize = 4;
+const RETRIES: u32 = 4;
+const BACKOFF_BASE: Duration = Duration::from_millis(500);
+const CHUNK_SIZE: usize = 64 * 1024;
+/// how long a connection may go without sending anything before it is retried
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// a completed download
+pub struct Fetched {
+    pub path: String,
+    /// nix base32 sha256 of the file, as used for store paths
+    pub hash: String,
+}
+
+enum Failure {
+    Retryable(String),
+    Fatal(String),
+}
+
+pub struct Downloader {
+    client: Client,
+    jobs: usize,
+    per_host: usize,
+    active_hosts: Mutex<HashMap<String, usize>>,
+    host_freed: Condvar,
+}
+
+/// releases a host connection slot when dropped
+struct HostSlot<'a> {
+    downloader: &'a Downloader,
+    host: String,
+}
+
+impl Drop for HostSlot<'_> {
+    fn drop(&mut self) {
+        let mut hosts = self
+            .downloader
+            .active_hosts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = hosts.get_mut(&self.host) {
+            *count -= 1;
+        }
+        self.downloader.host_freed.notify_all();
+    }
+}
+
+/// feeds downloaded bytes into every hash a download may be checked against
+struct Hashers {
+    sha256: Sha256,
+    sha512: Sha512,
+}
+
+impl Write for Hashers {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sha256.update(buf);
+        self.sha512.update(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Downloader {
+    pub fn new(jobs: usize, per_host: usize) -> Result<Self, String> {
+        let client = Client::builder()
+            .user_agent(crate::user_agent())
+            .connect_timeout(Duration::from_secs(30))
+            // the blocking client applies this to waiting for the response and to each read of
+            // the body, not to the whole transfer, so large files are fine but stalls are not
+            .timeout(IDLE_TIMEOUT)
+            .build()
+            .map_err(|e| format!("failed to build http client: {e}"))?;
+        Ok(Self {
+            client,
+            jobs: jobs.max(1),
+            per_host: per_host.max(1),
+            active_hosts: Mutex::new(HashMap::new()),
+            host_freed: Condvar::new(),
+        })
+    }
+
+    /// how many downloads may run at once
+    pub fn jobs(&self) -> usize {
+        self.jobs
+    }
+
+    fn acquire_host(&self, url: &str) -> HostSlot<'_> {
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(|h| h.to_string()))
+            .unwrap_or_default();
+        let mut hosts = self.active_hosts.lock().unwrap_or_else(|e| e.into_inner());
+        while hosts
+            .get(&host)
+            .is_some_and(|count| *count >= self.per_host)
+        {
+            hosts = self
+                .host_freed
+                .wait(hosts)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        *hosts.entry(host.clone()).or_insert(0) += 1;
+        HostSlot {
+            downloader: self,
+            host,
+        }
+    }
+
+    /// whether url can be fetched, without downloading it
+    pub fn exists(&self, url: &str) -> bool {
+        self.client
+            .head(url)
+            .send()
+            .is_ok_and(|response| response.status().is_success())
+    }
+
+    /// downloads url to dest, resuming a previous partial download and retrying transient
+    /// failures with exponential backoff.
+    /// prehash is checked against the file in the given format (`nix` or `sha512`)
+    pub fn fetch(
+        &self,
+        url: &str,
+        dest: &str,
+        prehash: Option<(&str, &str)>,
+    ) -> Result<Fetched, String> {
+        let _slot = self.acquire_host(url);
+        let name = Path::new(dest)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or(url.to_string());
+        let bar = progress().download(&name);
+        let mut attempt = 0;
+        let hashers = loop {
+            match self.try_fetch(url, dest, &bar) {
+                Ok(hashers) => break hashers,
+                Err(Failure::Retryable(e)) if attempt < RETRIES => {
+                    let backoff = BACKOFF_BASE * 2u32.pow(attempt);
+                    attempt += 1;
+                    progress::log(&format!(
+                        "{e}, retrying in {}ms ({attempt}/{RETRIES})",
+                        backoff.as_millis()
+                    ));
+                    thread::sleep(backoff);
+                }
+                Err(Failure::Retryable(e)) | Err(Failure::Fatal(e)) => return Err(e),
+            }
+        };
+        let part = part_path(dest, url);
+        let _ = fs::remove_file(validator_path(&part));
+        let hash = nix_base32::to_nix_base32(&hashers.sha256.finalize()[..]);
+        if let Some((prehash, hash_format)) = prehash {
+            let actual = match hash_format {
+                "nix" => hash.clone(),
+                "sha512" => format!("{:x}", hashers.sha512.finalize()),
+                _ => return Err(format!("unknown hash format {hash_format}")),
+            };
+            if actual != prehash {
+                let _ = fs::remove_file(&part);
+                return Err(format!(
+                    "{hash_format} checksum of {url} failed to validate (expected {prehash}, got {actual})"
+                ));
+            }
+        }
+        fs::rename(&part, dest)
+            .map_err(|e| format!("failed to move finished download to `{dest}`: {e}"))?;
+        Ok(Fetched {
+            path: dest.to_string(),
+            hash,
+        })
+    }
+
+    fn try_fetch(&self, url: &str, dest: &str, bar: &FileProgress) -> Result<Hashers, Failure> {
+        let part = part_path(dest, url);
+        let validator_file = validator_path(&part);
+        let mut hashers = Hashers {
+            sha256: Sha256::new(),
+            sha512: Sha512::new(),
+        };
+        // a partial file can only be resumed if the server can tell whether it changed since
+        let validator = fs::read_to_string(&validator_file).ok();
+        let resume_from = match validator {
+            Some(_) => fs::metadata(&part).map(|m| m.len()).unwrap_or(0),
+            None => 0,
+        };
+
+        let mut request = self.client.get(url);
+        if resume_from > 0
+            && let Some(validator) = &validator
+        {
+            request = request
+                .header(header::RANGE, format!("bytes={resume_from}-"))
+                .header(header::IF_RANGE, validator.trim());
+        }
+        let mut response = request
+            .send()
+            .map_err(|e| Failure::Retryable(format!("failed to download `{url}`: {e}")))?;
+        let status = response.status();
+        let resumed = match status {
+            StatusCode::PARTIAL_CONTENT => true,
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                // the partial file is stale or already complete, start over
+                let _ = fs::remove_file(&part);
+                let _ = fs::remove_file(&validator_file);
+                return Err(Failure::Retryable(format!(
+                    "server rejected resuming `{url}`"
+                )));
+            }
+            s if s.is_success() => false,
+            s if s.is_server_error()
+                || s == StatusCode::TOO_MANY_REQUESTS
+                || s == StatusCode::REQUEST_TIMEOUT =>
+            {
+                return Err(Failure::Retryable(format!(
+                    "failed to download `{url}`: server responded {s}"
+                )));
+            }
+            s => {
+                return Err(Failure::Fatal(format!(
+                    "failed to download `{url}`: server responded {s}"
+                )));
+            }
+        };
+
+        // a full response to an If-Range request means the file changed, so it starts over
+        if !resumed {
+            let validator = [header::ETAG, header::LAST_MODIFIED]
+                .iter()
+                .find_map(|name| response.headers().get(name)?.to_str().ok());
+            match validator {
+                Some(validator) => fs::write(&validator_file, validator),
+                None => fs::remove_file(&validator_file).or(Ok(())),
+            }
+            .map_err(|e| Failure::Fatal(format!("failed to write `{validator_file}`: {e}")))?;
+        }
+        let already = if resumed { resume_from } else { 0 };
+        if let Some(remaining) = response.content_length() {
+            bar.set_total(already + remaining);
+        }
+        bar.set_position(already);
+
+        let mut file = if resumed {
+            // bytes already on disk still have to go through the hashers
+            let mut existing = File::open(&part).map_err(|e| {
+                Failure::Fatal(format!("failed to open partial download `{part}`: {e}"))
+            })?;
+            io::copy(&mut existing, &mut hashers).map_err(|e| {
+                Failure::Fatal(format!("failed to read partial download `{part}`: {e}"))
+            })?;
+            OpenOptions::new().append(true).open(&part)
+        } else {
+            File::create(&part)
+        }
+        .map_err(|e| Failure::Fatal(format!("failed to open `{part}` for writing: {e}")))?;
+
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        loop {
+            let read = response.read(&mut buffer).map_err(|e| {
+                Failure::Retryable(format!("connection lost while downloading `{url}`: {e}"))
+            })?;
+            if read == 0 {
+                break;
+            }
+            file.write_all(&buffer[..read])
+                .map_err(|e| Failure::Fatal(format!("failed to write to disk `{part}`: {e}")))?;
+            let _ = hashers.write(&buffer[..read]);
+            bar.inc(read as u64);
+            progress().add_downloaded(read as u64);
+        }
+        Ok(hashers)
+    }
+}
+
+/// partial downloads are keyed on the url so different sources for one dest never mix
+fn part_path(dest: &str, url: &str) -> String {
+    format!("{dest}.{}.part", &hash_stream(url.as_bytes())[..16])
+}
+
+/// the ETag or Last-Modified of the response a partial download started from
+fn validator_path(part: &str) -> String {
+    format!("{part}.validator")
+}
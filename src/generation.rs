@@ -0,0 +1,435 @@
+// staged deployments of a target directory, swapped in as a whole once fully built
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::progress;
+
+/// generations older than this many are pruned after a successful swap
+const KEEP_GENERATIONS: usize = 2;
+
+/// a deployment of `target` built in `<target parent>/.jade/<target name>/gen-N`
+///
+/// on unix the target becomes a symlink to the current generation which is flipped with a single
+/// rename, elsewhere the generation directory itself is renamed over the target.
+pub struct Generation {
+    target: PathBuf,
+    generations_dir: PathBuf,
+    number: usize,
+    path: String,
+}
+
+impl Generation {
+    /// creates an empty generation directory for target, a real target directory deployed by an
+    /// older jade straight from the store at store_path becomes the first generation
+    pub fn stage(target: &str, store_path: &str) -> Result<Self, String> {
+        let target = Path::new(target.trim_end_matches(['/', '\\'])).to_path_buf();
+        let target_name = target
+            .file_name()
+            .ok_or(format!(
+                "target `{}` has no directory name",
+                target.display()
+            ))?
+            .to_string_lossy()
+            .to_string();
+        let parent = match target.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let generations_dir = parent.join(".jade").join(&target_name);
+        let adopt = check_plain_target(&target, &generations_dir, store_path)?;
+        fs::create_dir_all(&generations_dir).map_err(|e| {
+            format!(
+                "failed to create generations directory `{}`: {e}",
+                generations_dir.display()
+            )
+        })?;
+        let mut number = list_generations(&generations_dir)?
+            .iter()
+            .max()
+            .map_or(1, |n| n + 1);
+        if adopt {
+            adopt_plain_target(&target, &generations_dir, number)?;
+            number += 1;
+        }
+        let path = generations_dir.join(format!("gen-{number}"));
+        if path.exists() {
+            fs::remove_dir_all(&path).map_err(|e| {
+                format!("failed to clear stale generation `{}`: {e}", path.display())
+            })?;
+        }
+        fs::create_dir(&path)
+            .map_err(|e| format!("failed to create generation `{}`: {e}", path.display()))?;
+        Ok(Self {
+            target,
+            generations_dir,
+            number,
+            path: path.display().to_string(),
+        })
+    }
+
+    /// directory to deploy into
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// throws away a partially built generation, leaving the target untouched
+    pub fn abort(self) {
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            println!(
+                "warning: failed to remove aborted generation `{}`: {e}",
+                self.path
+            );
+        }
+    }
+
+    /// swaps the generation in as the target, old generations are only pruned once the
+    /// returned commit is finished
+    pub fn commit(self) -> Result<Committed, String> {
+        let previous = self.current();
+        if let Err(e) = self.swap() {
+            self.abort();
+            return Err(e);
+        }
+        progress::log(&format!(
+            "deployed generation {} to {}",
+            self.number,
+            self.target.display()
+        ));
+        Ok(Committed {
+            generation: self,
+            previous,
+        })
+    }
+
+    /// number of the generation the target currently is
+    #[cfg(unix)]
+    fn current(&self) -> Option<usize> {
+        fs::read_link(&self.target)
+            .ok()?
+            .file_name()?
+            .to_string_lossy()
+            .strip_prefix("gen-")?
+            .parse()
+            .ok()
+    }
+
+    #[cfg(not(unix))]
+    fn current(&self) -> Option<usize> {
+        // swap moves the outgoing target to the generation before this one
+        self.target.exists().then(|| self.number - 1)
+    }
+
+    /// removes a target that is a real, empty directory so the generation link can replace it
+    fn retire_plain_target(&self) -> Result<(), String> {
+        let is_plain = fs::symlink_metadata(&self.target)
+            .map(|m| !m.file_type().is_symlink())
+            .unwrap_or(false);
+        if is_plain {
+            fs::remove_dir(&self.target).map_err(|e| {
+                format!(
+                    "failed to replace existing target `{}`: {e}",
+                    self.target.display()
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn swap(&self) -> Result<(), String> {
+        self.retire_plain_target()?;
+        self.link(self.number)
+    }
+
+    /// atomically points the target at generation number
+    #[cfg(unix)]
+    fn link(&self, number: usize) -> Result<(), String> {
+        link_generation(&self.target, &self.generations_dir, number)
+    }
+
+    #[cfg(unix)]
+    fn unswap(&self, previous: Option<usize>) -> Result<(), String> {
+        match previous {
+            Some(previous) => self.link(previous)?,
+            None => {
+                // the target was an empty directory or did not exist before
+                fs::remove_file(&self.target)
+                    .map_err(|e| format!("failed to remove `{}`: {e}", self.target.display()))?;
+                fs::create_dir(&self.target)
+                    .map_err(|e| format!("failed to restore `{}`: {e}", self.target.display()))?;
+            }
+        }
+        fs::remove_dir_all(&self.path)
+            .map_err(|e| format!("failed to remove generation `{}`: {e}", self.path))
+    }
+
+    #[cfg(not(unix))]
+    fn swap(&self) -> Result<(), String> {
+        // directory symlinks need elevated privileges on windows, so fall back to renaming
+        if self.target.exists() {
+            let outgoing = self
+                .generations_dir
+                .join(format!("gen-{}", self.number - 1));
+            if outgoing.exists() {
+                let _ = fs::remove_dir_all(&outgoing);
+            }
+            fs::rename(&self.target, &outgoing).map_err(|e| {
+                format!(
+                    "failed to move current target `{}` aside: {e}",
+                    self.target.display()
+                )
+            })?;
+        }
+        fs::rename(&self.path, &self.target).map_err(|e| {
+            format!(
+                "failed to swap generation {} into `{}`: {e}",
+                self.number,
+                self.target.display()
+            )
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn unswap(&self, previous: Option<usize>) -> Result<(), String> {
+        fs::rename(&self.target, &self.path).map_err(|e| {
+            format!(
+                "failed to move generation {} out of `{}`: {e}",
+                self.number,
+                self.target.display()
+            )
+        })?;
+        if let Some(previous) = previous {
+            let outgoing = self.generations_dir.join(format!("gen-{previous}"));
+            fs::rename(&outgoing, &self.target).map_err(|e| {
+                format!(
+                    "failed to restore generation {previous} to `{}`: {e}",
+                    self.target.display()
+                )
+            })?;
+        }
+        let _ = fs::remove_dir_all(&self.path);
+        Ok(())
+    }
+
+    /// removes all but the newest generations so the previous one remains for rollback
+    fn prune(&self) {
+        let Ok(mut generations) = list_generations(&self.generations_dir) else {
+            return;
+        };
+        generations.sort_unstable_by(|a, b| b.cmp(a));
+        for number in generations.into_iter().skip(KEEP_GENERATIONS) {
+            let path = self.generations_dir.join(format!("gen-{number}"));
+            if let Err(e) = fs::remove_dir_all(&path) {
+                println!(
+                    "warning: failed to prune old generation `{}`: {e}",
+                    path.display()
+                );
+            }
+        }
+    }
+}
+
+/// refuses to take over a real target directory holding files jade did not deploy, swapping a
+/// generation in would hide them, returns whether the target only holds entries deployed from
+/// the store and has to be adopted as a generation first
+fn check_plain_target(
+    target: &Path,
+    generations_dir: &Path,
+    store_path: &str,
+) -> Result<bool, String> {
+    let is_plain = fs::symlink_metadata(target).is_ok_and(|m| m.is_dir());
+    if !is_plain {
+        return Ok(false);
+    }
+    // elsewhere generations are renamed over the target, so a plain target is one of them
+    if !cfg!(unix)
+        && list_generations(generations_dir).is_ok_and(|generations| !generations.is_empty())
+    {
+        return Ok(false);
+    }
+    let read_error =
+        |e: std::io::Error| format!("failed to read target `{}`: {e}", target.display());
+    let mut store_files = None;
+    let mut unmanaged = Vec::new();
+    let mut entries = 0;
+    for entry in target.read_dir().map_err(read_error)? {
+        let entry = entry.map_err(read_error)?;
+        entries += 1;
+        if !is_store_deployment(&entry.path(), Path::new(store_path), &mut store_files) {
+            unmanaged.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    if !unmanaged.is_empty() {
+        return Err(format!(
+            "`{}` already holds {} entries jade does not manage ({}), move them out of the way (or into the pack) so jade can deploy there",
+            target.display(),
+            unmanaged.len(),
+            unmanaged.join(", ")
+        ));
+    }
+    Ok(cfg!(unix) && entries > 0)
+}
+
+/// whether path is a symlink into the store or a file (or tree of files) hardlinked from it
+pub fn is_store_deployment(
+    path: &Path,
+    store: &Path,
+    store_files: &mut Option<HashSet<(u64, u64)>>,
+) -> bool {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return false;
+    };
+    if metadata.file_type().is_symlink() {
+        let canonical_store = fs::canonicalize(store).unwrap_or(store.to_path_buf());
+        return fs::read_link(path).is_ok_and(|link| {
+            link.starts_with(store)
+                || link.starts_with(&canonical_store)
+                || fs::canonicalize(path).is_ok_and(|p| p.starts_with(&canonical_store))
+        });
+    }
+    if metadata.is_dir() {
+        return fs::read_dir(path).is_ok_and(|entries| {
+            entries
+                .flatten()
+                .all(|entry| is_store_deployment(&entry.path(), store, store_files))
+        });
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        metadata.nlink() > 1
+            && store_files
+                .get_or_insert_with(|| {
+                    let mut files = HashSet::new();
+                    collect_files(store, &mut files);
+                    files
+                })
+                .contains(&(metadata.dev(), metadata.ino()))
+    }
+    #[cfg(not(unix))]
+    false
+}
+
+/// device and inode of every file under dir
+#[cfg(unix)]
+fn collect_files(dir: &Path, files: &mut HashSet<(u64, u64)>) {
+    use std::os::unix::fs::MetadataExt;
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            collect_files(&entry.path(), files);
+        } else {
+            files.insert((metadata.dev(), metadata.ino()));
+        }
+    }
+}
+
+/// moves a target deployed before generations existed to generation number and links it back
+#[cfg(unix)]
+fn adopt_plain_target(target: &Path, generations_dir: &Path, number: usize) -> Result<(), String> {
+    let path = generations_dir.join(format!("gen-{number}"));
+    fs::rename(target, &path).map_err(|e| {
+        format!(
+            "failed to move existing target `{}` to `{}`: {e}",
+            target.display(),
+            path.display()
+        )
+    })?;
+    progress::log(&format!(
+        "moved existing deployment in {} to generation {number}",
+        target.display()
+    ));
+    link_generation(target, generations_dir, number)
+}
+
+#[cfg(not(unix))]
+fn adopt_plain_target(_: &Path, _: &Path, _: usize) -> Result<(), String> {
+    Ok(())
+}
+
+/// atomically points target at generation number
+#[cfg(unix)]
+fn link_generation(target: &Path, generations_dir: &Path, number: usize) -> Result<(), String> {
+    let target_name = target.file_name().unwrap().to_string_lossy();
+    let link = Path::new(".jade")
+        .join(target_name.as_ref())
+        .join(format!("gen-{number}"));
+    let tmp_link = generations_dir.join(format!(".gen-{number}.link"));
+    let _ = fs::remove_file(&tmp_link);
+    std::os::unix::fs::symlink(&link, &tmp_link).map_err(|e| {
+        format!(
+            "failed to create generation link `{}`: {e}",
+            tmp_link.display()
+        )
+    })?;
+    fs::rename(&tmp_link, target).map_err(|e| {
+        format!(
+            "failed to swap generation {number} into `{}`: {e}",
+            target.display()
+        )
+    })
+}
+
+/// a generation swapped in as its target, which can still be undone until it is finished
+pub struct Committed {
+    generation: Generation,
+    previous: Option<usize>,
+}
+
+impl Committed {
+    /// keeps the generation, pruning ones too old to roll back to
+    pub fn finish(self) {
+        self.generation.prune();
+    }
+
+    /// swaps the target back to what it was before the generation was committed
+    pub fn rollback(self) {
+        let generation = &self.generation;
+        match generation.unswap(self.previous) {
+            Ok(()) => progress::log(&format!(
+                "rolled back generation {} of {}",
+                generation.number,
+                generation.target.display()
+            )),
+            Err(e) => println!(
+                "warning: failed to roll back generation {} of {}: {e}",
+                generation.number,
+                generation.target.display()
+            ),
+        }
+    }
+}
+
+fn list_generations(dir: &Path) -> Result<Vec<usize>, String> {
+    let mut generations = Vec::new();
+    for entry in dir.read_dir().map_err(|e| {
+        format!(
+            "failed to read generations directory `{}`: {e}",
+            dir.display()
+        )
+    })? {
+        let entry = entry.map_err(|e| {
+            format!(
+                "failed to read generations directory `{}`: {e}",
+                dir.display()
+            )
+        })?;
+        if let Some(number) = entry
+            .file_name()
+            .to_string_lossy()
+            .strip_prefix("gen-")
+            .and_then(|n| n.parse().ok())
+        {
+            generations.push(number);
+        }
+    }
+    Ok(generations)
+}
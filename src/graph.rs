@@ -0,0 +1,271 @@
+// the depends graph of a pack, for explaining why derivations are in it
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use colorize::AnsiColor;
+
+use crate::{
+    package::{Derivation, Reason},
+    resolver::{Requirement, dependency_name},
+};
+
+pub struct DependencyGraph<'a> {
+    nodes: BTreeMap<&'a str, &'a Derivation>,
+    /// derivation -> (dependency name, requirement as written)
+    edges: BTreeMap<&'a str, Vec<(&'a str, &'a str)>>,
+}
+
+impl<'a> DependencyGraph<'a> {
+    pub fn new(derivations: &'a [Derivation]) -> Self {
+        let mut nodes = BTreeMap::new();
+        let mut edges = BTreeMap::new();
+        for derivation in derivations {
+            nodes.insert(derivation.name.as_str(), derivation);
+            edges.insert(
+                derivation.name.as_str(),
+                derivation
+                    .depends
+                    .iter()
+                    .map(|d| (dependency_name(d), d.as_str()))
+                    .collect(),
+            );
+        }
+        Self { nodes, edges }
+    }
+
+    fn dependencies(&self, name: &str) -> &[(&'a str, &'a str)] {
+        self.edges.get(name).map(|e| e.as_slice()).unwrap_or(&[])
+    }
+
+    fn dependents(&self, name: &str) -> Vec<&'a str> {
+        self.edges
+            .iter()
+            .filter(|(_, dependencies)| dependencies.iter().any(|(d, _)| *d == name))
+            .map(|(dependent, _)| *dependent)
+            .collect()
+    }
+
+    /// derivations nothing else depends on, the ones installed on purpose
+    pub fn roots(&self) -> Vec<&'a str> {
+        self.nodes
+            .keys()
+            .filter(|name| self.dependents(name).is_empty())
+            .copied()
+            .collect()
+    }
+
+    /// dependencies no longer needed by any explicitly installed derivation
+    pub fn orphans(&self) -> Vec<&'a str> {
+        let mut needed: Vec<&'a str> = self
+            .nodes
+            .values()
+            .filter(|d| d.reason == Reason::Explicit)
+            .map(|d| d.name.as_str())
+            .collect();
+        let mut i = 0;
+        while i < needed.len() {
+            for (dependency, _) in self.dependencies(needed[i]) {
+                if !needed.contains(dependency) {
+                    needed.push(dependency);
+                }
+            }
+            i += 1;
+        }
+        self.nodes
+            .keys()
+            .filter(|name| !needed.contains(name))
+            .copied()
+            .collect()
+    }
+
+    /// every cycle in the graph, each listed from its first member back to it
+    pub fn cycles(&self) -> Vec<Vec<&'a str>> {
+        let mut cycles: Vec<Vec<&'a str>> = Vec::new();
+        for start in self.nodes.keys() {
+            let mut path = vec![*start];
+            self.find_cycles(&mut path, &mut cycles);
+        }
+        cycles
+    }
+
+    fn find_cycles(&self, path: &mut Vec<&'a str>, cycles: &mut Vec<Vec<&'a str>>) {
+        let current = path[path.len() - 1];
+        for (dependency, _) in self.dependencies(current) {
+            // each cycle is only walked from its smallest member
+            if *dependency == path[0] {
+                let mut cycle = path.clone();
+                cycle.push(dependency);
+                cycles.push(cycle);
+            } else if !path.contains(dependency) && *dependency > path[0] {
+                path.push(dependency);
+                self.find_cycles(path, cycles);
+                path.pop();
+            }
+        }
+    }
+
+    fn label(&self, name: &str, requirement: Option<&str>) -> String {
+        let mut label = name.to_string();
+        if let Some(version) = self.nodes.get(name).and_then(|d| d.version.as_ref()) {
+            label.push_str(&format!(" {version}"));
+        }
+        if let Some(requirement) = requirement.and_then(|r| Requirement::parse(r).ok())
+            && !requirement.req.is_any()
+        {
+            label.push_str(&format!(" (requires {})", requirement.req));
+        }
+        label
+    }
+
+    /// every derivation reachable from roots through depends, roots included
+    pub fn reachable_from(&self, roots: &[&'a str]) -> BTreeSet<&'a str> {
+        let mut reached: BTreeSet<&'a str> = roots.iter().copied().collect();
+        let mut queue: VecDeque<&'a str> = roots.iter().copied().collect();
+        while let Some(current) = queue.pop_front() {
+            for (dependency, _) in self.dependencies(current) {
+                if reached.insert(dependency) {
+                    queue.push_back(dependency);
+                }
+            }
+        }
+        reached
+    }
+
+    /// prints name and everything it depends on, subtrees already printed are not expanded again
+    pub fn print_tree(&self, name: &'a str) {
+        println!("{}", self.label(name, None));
+        self.print_subtree(name, "", &mut vec![name], &mut BTreeSet::new());
+    }
+
+    fn print_subtree(
+        &self,
+        name: &str,
+        prefix: &str,
+        path: &mut Vec<&'a str>,
+        expanded: &mut BTreeSet<&'a str>,
+    ) {
+        let dependencies = self.dependencies(name);
+        for (i, (dependency, requirement)) in dependencies.iter().enumerate() {
+            let last = i == dependencies.len() - 1;
+            let branch = if last { "└── " } else { "├── " };
+            let label = self.label(dependency, Some(requirement));
+            if path.contains(dependency) {
+                println!("{prefix}{branch}{label} {}", "(cycle)".red());
+                continue;
+            }
+            if !self.nodes.contains_key(dependency) {
+                let embedder = self
+                    .nodes
+                    .values()
+                    .find(|d| d.embedded.iter().any(|e| e == dependency));
+                match embedder {
+                    Some(embedder) => println!(
+                        "{prefix}{branch}{label} {}",
+                        format!("(embedded in {})", embedder.name).yellow()
+                    ),
+                    None => println!("{prefix}{branch}{label} {}", "(missing)".red()),
+                }
+                continue;
+            }
+            if !self.dependencies(dependency).is_empty() && !expanded.insert(dependency) {
+                println!("{prefix}{branch}{label} (*)");
+                continue;
+            }
+            println!("{prefix}{branch}{label}");
+            path.push(dependency);
+            let prefix = format!("{prefix}{}", if last { "    " } else { "│   " });
+            self.print_subtree(dependency, &prefix, path, expanded);
+            path.pop();
+        }
+    }
+
+    /// every path from a root that needs name down to name, at most limit of them, and whether
+    /// there were more
+    pub fn paths_to(&self, name: &'a str, limit: usize) -> (Vec<Vec<&'a str>>, bool) {
+        // dependents only reachable through a cycle nothing else depends on have no root, their
+        // paths start where the cycle closes
+        let rooted = self.reachable_from(&self.roots());
+        let mut paths = Vec::new();
+        let mut path = vec![name];
+        let more = self.walk_dependents(&rooted, &mut path, &mut paths, limit);
+        for path in &mut paths {
+            path.reverse();
+        }
+        paths.sort_by(|a, b| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+        (paths, more)
+    }
+
+    /// extends path, which runs from name up to its last element, through every dependent not
+    /// already on it, returns true once more than limit paths were found
+    fn walk_dependents(
+        &self,
+        rooted: &BTreeSet<&'a str>,
+        path: &mut Vec<&'a str>,
+        paths: &mut Vec<Vec<&'a str>>,
+        limit: usize,
+    ) -> bool {
+        let current = path[path.len() - 1];
+        let all = self.dependents(current);
+        let dependents: Vec<&'a str> = all.iter().filter(|d| !path.contains(d)).copied().collect();
+        let is_end = all.is_empty() || (dependents.is_empty() && !rooted.contains(current));
+        if is_end {
+            if paths.len() == limit {
+                return true;
+            }
+            paths.push(path.clone());
+            return false;
+        }
+        for dependent in dependents {
+            path.push(dependent);
+            let more = self.walk_dependents(rooted, path, paths, limit);
+            path.pop();
+            if more {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// the whole graph in graphviz dot, edges on a cycle are drawn red
+    pub fn to_dot(&self, pack: &str) -> String {
+        let cycles = self.cycles();
+        let on_cycle = |from: &str, to: &str| {
+            cycles
+                .iter()
+                .any(|cycle| cycle.windows(2).any(|w| w[0] == from && w[1] == to))
+        };
+        let mut dot = format!("digraph \"{pack}\" {{\n");
+        for (name, derivation) in &self.nodes {
+            let label = match &derivation.version {
+                Some(version) => format!("{name}\\n{version}"),
+                None => name.to_string(),
+            };
+            dot.push_str(&format!("    \"{name}\" [label=\"{label}\"];\n"));
+        }
+        for (name, dependencies) in &self.edges {
+            for (dependency, requirement) in dependencies {
+                let mut attributes = Vec::new();
+                if let Ok(requirement) = Requirement::parse(requirement)
+                    && !requirement.req.is_any()
+                {
+                    attributes.push(format!("label=\"{}\"", requirement.req));
+                }
+                if on_cycle(name, dependency) {
+                    attributes.push("color=red".to_string());
+                }
+                if !self.nodes.contains_key(dependency) {
+                    attributes.push("style=dashed".to_string());
+                }
+                let attributes = if attributes.is_empty() {
+                    String::new()
+                } else {
+                    format!(" [{}]", attributes.join(", "))
+                };
+                dot.push_str(&format!(
+                    "    \"{name}\" -> \"{dependency}\"{attributes};\n"
+                ));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
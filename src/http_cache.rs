@@ -0,0 +1,99 @@
+// on-disk cache of api responses, revalidated with the server's etag once they expire
+use std::{fs, path::Path, sync::OnceLock};
+
+use chrono::Utc;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const DEFAULT_TTL_SECS: u64 = 60 * 60;
+
+static CACHE: OnceLock<HttpCache> = OnceLock::new();
+
+/// sets up the process wide cache, responses older than ttl are revalidated and refresh
+/// revalidates every response
+pub fn configure(dir: &str, ttl: u64, refresh: bool) {
+    let _ = CACHE.set(HttpCache {
+        dir: dir.to_string(),
+        ttl: ttl as i64,
+        refresh,
+    });
+}
+
+pub fn http_cache() -> Option<&'static HttpCache> {
+    CACHE.get()
+}
+
+pub struct HttpCache {
+    pub dir: String,
+    ttl: i64,
+    refresh: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CachedResponse {
+    pub url: String,
+    pub etag: Option<String>,
+    /// unix time the response was fetched or last revalidated
+    pub fetched: i64,
+    pub body: String,
+}
+
+impl HttpCache {
+    fn entry_path(&self, url: &str) -> String {
+        format!("{}/{:x}.json", self.dir, Sha256::digest(url.as_bytes()))
+    }
+
+    pub fn lookup(&self, url: &str) -> Option<CachedResponse> {
+        let contents = fs::read_to_string(self.entry_path(url)).ok()?;
+        let cached: CachedResponse = serde_json::from_str(&contents).ok()?;
+        // guards against hash collisions and hand edited files
+        (cached.url == url).then_some(cached)
+    }
+
+    /// whether a cached response can be used without asking the server
+    pub fn is_fresh(&self, cached: &CachedResponse) -> bool {
+        !self.refresh && Utc::now().timestamp() - cached.fetched < self.ttl
+    }
+
+    pub fn store(&self, url: &str, etag: Option<String>, body: &str) -> Result<(), String> {
+        self.write(&CachedResponse {
+            url: url.to_string(),
+            etag,
+            fetched: Utc::now().timestamp(),
+            body: body.to_string(),
+        })
+    }
+
+    /// marks a response the server reported as unchanged as fresh again
+    pub fn touch(&self, cached: &CachedResponse) -> Result<(), String> {
+        let mut cached = cached.clone();
+        cached.fetched = Utc::now().timestamp();
+        self.write(&cached)
+    }
+
+    fn write(&self, cached: &CachedResponse) -> Result<(), String> {
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| format!("failed to create http cache `{}`: {e}", self.dir))?;
+        let path = self.entry_path(&cached.url);
+        let serialized = serde_json::to_string(cached)
+            .map_err(|e| format!("failed to serialize cached response: {e}"))?;
+        let tmp = format!("{path}.tmp");
+        fs::write(&tmp, serialized)
+            .map_err(|e| format!("failed to write cached response `{tmp}`: {e}"))?;
+        fs::rename(&tmp, &path)
+            .map_err(|e| format!("failed to write cached response `{path}`: {e}"))
+    }
+
+    /// removes every cached response, returning how many there were
+    pub fn clear(&self) -> Result<usize, String> {
+        if !Path::new(&self.dir).exists() {
+            return Ok(0);
+        }
+        let count = fs::read_dir(&self.dir)
+            .map_err(|e| format!("failed to read http cache `{}`: {e}", self.dir))?
+            .count();
+        fs::remove_dir_all(&self.dir)
+            .map_err(|e| format!("failed to clear http cache `{}`: {e}", self.dir))?;
+        Ok(count)
+    }
+}
@@ -1,7 +1,7 @@
 // parse packwiz files and collect all their urls and then download
 const VERSION: &str = "1.0";
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashSet},
     env,
     fs::{self, File, create_dir, create_dir_all},
     io::{Write, read_to_string},
@@ -11,21 +11,37 @@ use std::{
 };
 mod api;
 mod api_driver;
+mod closure;
+mod download;
+mod generation;
+mod graph;
+mod http_cache;
 mod util;
 mod verbose;
+use api::OptionalDeps;
 use colorize::AnsiColor;
+use download::Downloader;
+use generation::Generation;
+use graph::DependencyGraph;
 use manifest::Manifest;
-use package::{Derivations, load_derivations_from_directory};
+use package::{Derivation, Derivations, Kind, Provenance, Reason, Side, load_pack_derivations};
+use progress::progress;
+use shared_target::SharedTarget;
 // use preprocessor::dedup;
-use store::Store;
+use store::{DeployMode, Integrity, Store, StorePath};
 // mod _composer;
 // mod _package;
 // mod _boostrap;
 mod manifest;
 mod package;
+mod progress;
+mod resolver;
+mod serve;
+mod shared_target;
 // mod _packwiz_compat;
 // mod _preprocessor;
 mod store;
+mod store_db;
 use clap::Parser;
 use util::normalize;
 
@@ -64,7 +80,27 @@ struct Args {
     #[arg(long)]
     copy: bool,
     #[arg(long)]
+    hardlink: bool,
+    #[arg(long)]
     complete: bool,
+    /// never touch the network, only use the store and local substituters (also JADE_OFFLINE)
+    #[arg(long)]
+    offline: bool,
+    /// ignore cached api responses younger than --cache-ttl and revalidate them
+    #[arg(long)]
+    refresh: bool,
+    /// seconds an api response is used without revalidating it
+    #[arg(long, default_value_t = http_cache::DEFAULT_TTL_SECS)]
+    cache_ttl: u64,
+    /// pack profile from the manifest to use, e.g. `jade compose --profile server`
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    /// maximum number of concurrent downloads
+    #[arg(long, default_value_t = download::DEFAULT_JOBS)]
+    jobs: usize,
+    /// maximum number of concurrent downloads from a single host
+    #[arg(long, default_value_t = download::DEFAULT_CONNECTIONS_PER_HOST)]
+    connections_per_host: usize,
     #[command(subcommand)]
     command: Commands,
 }
@@ -91,6 +127,9 @@ enum Commands {
         // source: Option<String>,
         #[arg(short, long)]
         target: Option<String>,
+        /// only deploy derivations that run on this side (client, server, or both for everything)
+        #[arg(long)]
+        side: Option<String>,
     },
     Edit {
         modname: String,
@@ -100,14 +139,119 @@ enum Commands {
     Check {},
     Search {
         query: String,
+        /// kind of project to search for (mod, resourcepack, shader or datapack)
+        #[arg(long = "type", default_value = "mod")]
+        kind: String,
     },
     Install {
+        /// mod names, optionally pinned to a version as `name@version`
+        mods: Vec<String>,
+        /// kind of project to install (mod, resourcepack, shader or datapack)
+        #[arg(long = "type", default_value = "mod")]
+        kind: String,
+        /// choose the version of each mod from a list instead of taking the newest
+        #[arg(long)]
+        pick_version: bool,
+        /// install optional dependencies without asking
+        #[arg(long)]
+        with_optional: bool,
+    },
+    /// update api managed derivations to the newest versions compatible with the pack
+    Update {
         mods: Vec<String>,
     },
     List {
         filter: Option<String>,
+        /// only list dependencies nothing explicitly installed needs anymore
+        #[arg(long)]
+        orphans: bool,
+    },
+    /// deploy mods disabled in the manifest again
+    Enable {
+        mods: Vec<String>,
+    },
+    /// keep mods in the pack but stop deploying them
+    Disable {
+        mods: Vec<String>,
+    },
+    /// remove mods from the pack along with the dependencies only they needed
+    Remove {
+        mods: Vec<String>,
+    },
+    /// print what a mod depends on, or the whole pack from its top-level mods
+    Tree {
+        modname: Option<String>,
+    },
+    /// print every path from a top-level mod that needs a mod down to it
+    Why {
+        modname: String,
+        /// most paths to print
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+    },
+    /// print the dependency graph of the pack
+    Graph {
+        #[arg(long, default_value = "dot")]
+        format: String,
     },
     Version,
+    Store {
+        #[command(subcommand)]
+        command: StoreCommands,
+    },
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+    /// serve the store over http so other machines can use it as a substituter
+    ServeStore {
+        #[arg(long, default_value = "0.0.0.0:7878")]
+        listen: String,
+    },
+}
+#[derive(clap::Subcommand, Debug)]
+enum StoreCommands {
+    /// re-hash every store entry and compare it against the hash in its path
+    Verify,
+    /// re-download corrupted store entries from their origin
+    Repair,
+    /// list store entries
+    Ls,
+    /// show what is known about store entries matching name
+    Info { name: String },
+    /// remove entries no longer used by any pack
+    Gc {
+        #[arg(long)]
+        dry_run: bool,
+        /// also remove entries the store database does not know, e.g. left by an interrupted
+        /// install, entries added before the database existed are untracked too
+        #[arg(long)]
+        untracked: bool,
+    },
+    /// bundle every store path a pack needs into a closure for machines without network access
+    Export {
+        /// pack directory, defaults to the current directory
+        pack: Option<String>,
+        #[arg(short, long)]
+        output: String,
+    },
+    /// verify and add the store paths of a closure to the store
+    Import {
+        closure: String,
+        /// restore the pack manifest and derivations into this directory, or verify directory
+        /// artifacts against the pins of the pack already there
+        #[arg(long)]
+        pack: Option<String>,
+        /// skip directory artifacts whose content hash is only known from the closure itself
+        #[arg(long)]
+        strict: bool,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum CacheCommands {
+    /// remove every cached api response
+    Clear,
 }
 
 // #[cfg(target_os = "windows")]
@@ -136,7 +280,7 @@ fn get_jade_root() -> Result<String, String> {
 }
 
 fn load_context(dir: &str, args: &Args) -> Result<(Manifest, String), String> {
-    let manifest = if let Some(manifest) = &args.manifest {
+    let mut manifest = if let Some(manifest) = &args.manifest {
         Manifest::load(&manifest)?
     } else {
         let manifest_path = format!("{dir}/{MANIFEST}");
@@ -151,6 +295,10 @@ fn load_context(dir: &str, args: &Args) -> Result<(Manifest, String), String> {
         }
     };
 
+    if let Some(profile) = &args.profile {
+        manifest.use_profile(profile)?;
+    }
+
     let fallback = format!("{dir}/derives/");
     let derives = if let Some(derives) = &args.derives {
         derives.clone()
@@ -169,6 +317,48 @@ fn load_context(dir: &str, args: &Args) -> Result<(Manifest, String), String> {
 
     Ok((manifest, derives))
 }
+/// warns about enabled derivations requiring a disabled one
+fn warn_disabled_dependencies(manifest: &Manifest, derivations: &[Derivation]) {
+    for derivation in derivations {
+        if !manifest.is_enabled(&derivation.name) {
+            continue;
+        }
+        for dependency in &derivation.depends {
+            let name = resolver::dependency_name(dependency);
+            if !manifest.is_enabled(name) && derivations.iter().any(|d| d.name == name) {
+                println!(
+                    "{} {} requires {name}, which is disabled",
+                    "warning:".yellow(),
+                    derivation.name
+                );
+            }
+        }
+    }
+}
+
+/// the derivations the manifest enables, limited to the active profile
+fn enabled_derivations(manifest: &Manifest, derivations: Vec<Derivation>) -> Vec<Derivation> {
+    let derivations = match &manifest.profile {
+        Some(profile) => {
+            let selected = profile.select(derivations);
+            println!(
+                "using profile {}, {} derivation(s) selected",
+                profile.name,
+                selected.len()
+            );
+            selected
+        }
+        None => derivations,
+    };
+    warn_disabled_dependencies(manifest, &derivations);
+    let (enabled, disabled): (Vec<_>, Vec<_>) = derivations
+        .into_iter()
+        .partition(|d| manifest.is_enabled(&d.name));
+    if !disabled.is_empty() {
+        println!("skipping {} disabled derivation(s)", disabled.len());
+    }
+    enabled
+}
 // fn get_temp()
 fn entry(args: Args) -> Result<(), String> {
     if let Some(cwd) = &args.cwd {
@@ -186,21 +376,21 @@ fn entry(args: Args) -> Result<(), String> {
         format!("{root}/store/")
     };
 
-    let store = Store::new(&store_path, &format!("{root}/staging"));
+    util::set_offline(
+        args.offline || env::var("JADE_OFFLINE").is_ok_and(|v| !v.is_empty() && v != "0"),
+    );
+    http_cache::configure(&format!("{root}/cache/http"), args.cache_ttl, args.refresh);
+    let downloader = Downloader::new(args.jobs, args.connections_per_host)?;
+    let mut store = Store::new(&store_path, &format!("{root}/staging"), downloader);
 
-    let symlink = if args.symlink {
-        true
+    let deploy_flag = if args.symlink {
+        Some(DeployMode::Symlink)
     } else if args.copy {
-        false
+        Some(DeployMode::Copy)
+    } else if args.hardlink {
+        Some(DeployMode::Hardlink)
     } else {
-        #[cfg(target_os = "windows")]
-        {
-            false
-        }
-        #[cfg(not(target_os = "windows"))]
-        {
-            true
-        }
+        None
     };
 
     match args.command {
@@ -227,22 +417,112 @@ fn entry(args: Args) -> Result<(), String> {
                 .map_err(|e| format!("failed to write to manifest file {e}"))?;
         }
         Commands::BootStrap { manifest } => todo!(),
-        Commands::Compose { ref target } => {
+        Commands::Compose {
+            ref target,
+            ref side,
+        } => {
             let (manifest, derives) = load_context("./", &args)?;
             let target = if let Some(target) = target {
                 target.to_string()
-            } else if let Some(target) = manifest.main.target {
-                target
+            } else if let Some(target) = &manifest.main.target {
+                target.clone()
             } else {
                 return Err(format!(
                     "no target specified, either add this to the pack manifest (target = \"/path/to/target\") or manually specify with the --target flag"
                 ));
             };
-            let derivations = load_derivations_from_directory(&Path::new(&derives))?;
+            let deploy_mode = if let Some(mode) = deploy_flag {
+                mode
+            } else if let Some(mode) = &manifest.main.deploy {
+                DeployMode::parse(mode)?
+            } else {
+                DeployMode::platform_default()
+            };
+            store.substituters = manifest.main.substituters.clone().unwrap_or_default();
+            let mut derivations =
+                enabled_derivations(&manifest, load_pack_derivations(&manifest, &derives)?);
+            if let Some(side) = side {
+                let side = Side::parse(side)?;
+                let (kept, skipped): (Vec<_>, Vec<_>) =
+                    derivations.into_iter().partition(|d| d.side.runs_on(side));
+                for derivation in &kept {
+                    for skipped in skipped.iter().filter(|s| {
+                        derivation
+                            .depends
+                            .iter()
+                            .any(|d| resolver::dependency_name(d) == s.name)
+                    }) {
+                        println!(
+                            "{} {} depends on {} which is {}-only",
+                            "warning:".yellow(),
+                            derivation.name,
+                            skipped.name,
+                            skipped.side
+                        );
+                    }
+                }
+                println!(
+                    "composing for {side}, skipping {} derivation(s)",
+                    skipped.len()
+                );
+                derivations = kept;
+            }
+            progress().start();
             let (paths, derivations) = store.realize_derivations(derivations)?;
-            for path in paths {
-                path.install_to(&target, symlink)?;
+            // each kind is deployed to its own directory, the mods target is always recomposed
+            let mut by_kind: BTreeMap<Kind, Vec<&StorePath>> = BTreeMap::new();
+            by_kind.insert(Kind::Mod, Vec::new());
+            for (path, derivation) in paths.iter().zip(&derivations) {
+                by_kind.entry(derivation.kind).or_default().push(path);
+            }
+            let targets = by_kind
+                .keys()
+                .map(|kind| Ok((*kind, manifest.target_for(*kind, &target)?)))
+                .collect::<Result<BTreeMap<Kind, String>, String>>()?;
+            // other kinds share their directory with the user, kinds no longer in the pack are
+            // visited too so what jade placed there before is removed
+            let mut shared = Vec::new();
+            for kind in Kind::ALL.into_iter().filter(|kind| *kind != Kind::Mod) {
+                let target = match targets.get(&kind) {
+                    Some(target) => target.clone(),
+                    None => match manifest.target_for(kind, &target) {
+                        Ok(target) => target,
+                        Err(_) => continue,
+                    },
+                };
+                let shared_target = SharedTarget::open(&target, &store.store_path)?;
+                if by_kind.contains_key(&kind) || shared_target.is_used() {
+                    shared.push((kind, shared_target));
+                }
             }
+            // mods are deployed into a fresh generation so a failure never leaves them half updated
+            progress().phase("deploying", paths.len());
+            let generation = Generation::stage(&targets[&Kind::Mod], &store.store_path)?;
+            if let Err(e) = by_kind[&Kind::Mod]
+                .iter()
+                .try_for_each(|path| path.install_to(generation.path(), deploy_mode))
+            {
+                generation.abort();
+                return Err(e);
+            }
+            let committed = generation.commit()?;
+            for (kind, shared_target) in &mut shared {
+                let paths = by_kind.get(kind).map(Vec::as_slice).unwrap_or_default();
+                if let Err(e) = shared_target.deploy(paths, deploy_mode, &store.store_path) {
+                    committed.rollback();
+                    return Err(e);
+                }
+            }
+            committed.finish();
+            // references are kept per target, so composing a profile or side elsewhere keeps these
+            let deployed_to = std::path::absolute(&targets[&Kind::Mod])
+                .map_err(|e| format!("failed to resolve target `{}`: {e}", targets[&Kind::Mod]))?;
+            store.reference_pack(
+                &manifest.backing_file,
+                Some(&deployed_to.display().to_string()),
+                &derivations,
+            )?;
+            progress().summary();
         }
         Commands::Edit {
             ref modname,
@@ -264,16 +544,100 @@ fn entry(args: Args) -> Result<(), String> {
             let path = {
                 // find derivation based on name field
                 // let derivations = load_derivations_from_directory(&Path::new(&derives))?;
-                let derivations = Derivations::load_derivations_from_directory(&derives)?;
-                derivations
-                    .get_derivation_by_fuzzy_name(&normalized_modname)?
-                    .backing_file
-                    .clone()
+                let derivations = Derivations::load_pack(&manifest, &derives)?;
+                let derivation = derivations.get_derivation_by_fuzzy_name(&normalized_modname)?;
+                match &derivation.provenance {
+                    Provenance::Included { pack, file } => {
+                        println!(
+                            "{} {} is defined by {pack}, editing it changes every pack including {pack}",
+                            "warning:".yellow(),
+                            derivation.name
+                        );
+                        file.clone()
+                    }
+                    _ => derivation.backing_file.clone(),
+                }
             };
             process::Command::new(editor).arg(&path).output();
         }
-        Commands::Check {} => todo!(),
-        Commands::Search { ref query } => {
+        Commands::Check {} => {
+            let (manifest, derives) = load_context("./", &args)?;
+            let mut derivations = load_pack_derivations(&manifest, &derives)?;
+            // versions missing from older derivations are looked up so their requirements can hold
+            if !util::is_offline()
+                && let Some(api_name) = &manifest.main.api
+                && derivations
+                    .iter()
+                    .any(|d| d.version.is_none() && d.apiverid.is_some())
+            {
+                let driver = api::get_api_driver(api_name, &manifest.api_cfg)?;
+                if let Err(e) = resolver::fill_versions(driver.as_ref(), &mut derivations) {
+                    println!(
+                        "{} could not look up missing versions: {e}",
+                        "warning:".yellow()
+                    );
+                }
+            }
+            let mut corrupt = Vec::new();
+            for derivation in &derivations {
+                if derivation.hash.is_none() {
+                    println!(
+                        "{} {} (no hash pinned)",
+                        "unpinned".yellow(),
+                        derivation.name
+                    );
+                    continue;
+                }
+                let Some(path) = store.is_package_in_store(derivation) else {
+                    println!("{} {} (not in store)", "missing".yellow(), derivation.name);
+                    continue;
+                };
+                match path.verify()? {
+                    Integrity::Valid => println!("{} {}", "ok".green(), derivation.name),
+                    Integrity::Corrupt { actual } => {
+                        println!(
+                            "{} {} (`{path}` hashes to {actual})",
+                            "corrupt".red(),
+                            derivation.name
+                        );
+                        corrupt.push(derivation.name.clone());
+                    }
+                    Integrity::Missing => {
+                        println!("{} {} (artifact missing)", "corrupt".red(), derivation.name);
+                        corrupt.push(derivation.name.clone());
+                    }
+                    Integrity::Unverifiable(reason) => {
+                        println!("{} {} ({reason})", "unverified".yellow(), derivation.name)
+                    }
+                }
+            }
+            let problems = resolver::check(&derivations)?;
+            for problem in &problems {
+                println!("{} {}", "unresolved".red(), problem.explain(&derivations));
+            }
+            if !corrupt.is_empty() {
+                return Err(format!(
+                    "corrupt store entries for {}, run `jade store repair`",
+                    corrupt.join(", ")
+                ));
+            }
+            if !problems.is_empty() {
+                return Err(format!(
+                    "{} dependency problem(s) in the pack",
+                    problems.len()
+                ));
+            }
+        }
+        Commands::Search {
+            ref query,
+            ref kind,
+        } => {
+            if util::is_offline() {
+                return Err(
+                    "search queries the api and needs network access, run it without --offline"
+                        .to_string(),
+                );
+            }
             let (manifest, derives) = load_context("./", &args)?;
             let api_name = if let Some(name) = manifest.main.api {
                 name
@@ -281,28 +645,41 @@ fn entry(args: Args) -> Result<(), String> {
                 return Err(format!("no api driver specified"));
             };
             let driver = api::get_api_driver(&api_name, &manifest.api_cfg)?;
-            let results = driver.search(query)?;
+            let results = driver.search(query, Kind::parse(kind)?)?;
             println!("{} result(s) for {query}", results.len());
             for (i, result) in results.iter().enumerate() {
                 println!("{} {result}\n--", format!("{i})").red());
             }
         }
-        Commands::Install { ref mods } => {
+        Commands::Install {
+            ref mods,
+            ref kind,
+            pick_version,
+            with_optional,
+        } => {
+            let kind = Kind::parse(kind)?;
+            if util::is_offline() {
+                return Err("install resolves mods through the api and needs network access, run it without --offline".to_string());
+            }
             let (manifest, derives) = load_context("./", &args)?;
-            let api_name = if let Some(name) = manifest.main.api {
+            let api_name = if let Some(name) = manifest.main.api.clone() {
                 name
             } else {
                 return Err(format!("no api driver specified"));
             };
             let driver = api::get_api_driver(&api_name, &manifest.api_cfg)?;
 
-            let mut derivations = Derivations::load_derivations_from_directory(&derives)?;
+            let mut derivations = Derivations::load_pack(&manifest, &derives)?;
 
             let mut mod_set = HashSet::new();
-            for slug in mods {
+            for spec in mods {
+                let (slug, version) = match spec.split_once('@') {
+                    Some((slug, version)) => (slug.to_string(), Some(version.to_string())),
+                    None => (spec.clone(), None),
+                };
                 let mut install = true;
                 for derive in &derivations.derivations {
-                    if derive.name.contains(&normalize(slug)) {
+                    if derive.name.contains(&normalize(&slug)) {
                         if !confirm(
                             &format!(
                                 "{slug} potentionally already installed in tree ({}) reinstall?",
@@ -315,13 +692,13 @@ fn entry(args: Args) -> Result<(), String> {
                     }
                 }
                 if install {
-                    mod_set.insert(slug);
+                    mod_set.insert((slug, version));
                 }
             }
 
             let mut pkg_ids = Vec::new();
-            for (i, slug) in mod_set.iter().enumerate() {
-                let results = driver.search(slug)?;
+            for (i, (slug, version)) in mod_set.iter().enumerate() {
+                let results = driver.search(slug, kind)?;
                 if results.is_empty() {
                     return Err(format!("no results for {slug}"));
                 }
@@ -329,7 +706,7 @@ fn entry(args: Args) -> Result<(), String> {
                     println!("{}", results[0]);
                     let resp = confirm(&format!("install {}?", results[0].slug), true)?;
                     if resp {
-                        pkg_ids.push(results[0].id.clone());
+                        pkg_ids.push((results[0].id.clone(), version.clone()));
                     }
                 } else {
                     for (i, result) in results.iter().enumerate() {
@@ -341,22 +718,57 @@ fn entry(args: Args) -> Result<(), String> {
                         0,
                         results.len() as isize - 1,
                     )?;
-                    pkg_ids.push(results[n as usize].id.clone());
+                    pkg_ids.push((results[n as usize].id.clone(), version.clone()));
                 }
             }
-            let mut new_derivations = Vec::new();
-            for id in pkg_ids {
-                let derives = driver.get_derivations_for(
-                    &id,
-                    &mut derivations.get_api_pkg_id_list(),
-                    true,
-                    &store,
+            let mut packages = Vec::new();
+            for (id, version) in pkg_ids {
+                if version.is_some() || !pick_version {
+                    packages.push((id, version));
+                    continue;
+                }
+                let versions = driver.list_versions(&id)?;
+                if versions.is_empty() {
+                    return Err(format!("no versions of {id} are compatible with the pack"));
+                }
+                for (i, version) in versions.iter().enumerate() {
+                    println!("{} {version}", format!("{i})").red());
+                }
+                let n = select_index(
+                    &format!("select version of {id} to install"),
+                    0,
+                    0,
+                    versions.len() as isize - 1,
                 )?;
-                new_derivations.extend(derives);
+                packages.push((id, Some(versions[n as usize].id.clone())));
             }
+            let optional = if with_optional {
+                OptionalDeps::All
+            } else {
+                OptionalDeps::Ask
+            };
+            let new_derivations = driver.get_derivations_for(
+                &packages,
+                &mut derivations.get_api_pkg_id_list(),
+                optional,
+                true,
+                &store,
+            )?;
             let mut install_derives = Vec::new();
             for mut derive in new_derivations {
+                // only what was asked for is explicit, dependencies keep how they got in the pack
+                let requested = packages
+                    .iter()
+                    .any(|(id, _)| derive.apipkgid.as_ref() == Some(id));
+                derive.reason = if requested {
+                    Reason::Explicit
+                } else {
+                    Reason::Dependency
+                };
                 if let Some((found, installed)) = derivations.find_unmanaged_matches(&derive) {
+                    if !requested {
+                        derive.reason = found.reason;
+                    }
                     let prompt = if installed {
                         format!(
                             "\nderivation for `{}` already installed ({}) and managed by {} driver\noverride?",
@@ -377,6 +789,28 @@ fn entry(args: Args) -> Result<(), String> {
                     install_derives.push(derive);
                 }
             }
+            let install_derives = resolver::resolve(
+                driver.as_ref(),
+                &derivations.derivations,
+                install_derives,
+                &derives,
+                &store,
+            )
+            .map_err(|e| format!("{e}\nnothing was installed"))?;
+            for derive in &install_derives {
+                for embedded in &derive.embedded {
+                    if let Some(existing) =
+                        derivations.derivations.iter().find(|d| d.name == *embedded)
+                    {
+                        println!(
+                            "{} {} already ships {embedded}, `{}` can likely be removed",
+                            "warning:".yellow(),
+                            derive.name,
+                            existing.backing_file
+                        );
+                    }
+                }
+            }
             for (i, derive) in install_derives.iter().enumerate() {
                 println!(
                     "({}/{}) installing derivation for {}",
@@ -392,25 +826,463 @@ fn entry(args: Args) -> Result<(), String> {
                 println!("complete! ")
             }
         }
-        Commands::List { ref filter } => {
+        Commands::Update { ref mods } => {
+            if util::is_offline() {
+                return Err(
+                    "update checks the api for new versions and needs network access, run it without --offline"
+                        .to_string(),
+                );
+            }
+            let (manifest, derives) = load_context("./", &args)?;
+            let api_name = if let Some(name) = manifest.main.api.clone() {
+                name
+            } else {
+                return Err("no api driver specified".to_string());
+            };
+            let driver = api::get_api_driver(&api_name, &manifest.api_cfg)?;
+            let derivations = Derivations::load_pack(&manifest, &derives)?;
+            let mut candidates = Vec::new();
+            for name in mods {
+                candidates.push(
+                    derivations
+                        .get_derivation_by_fuzzy_name(&normalize(name))?
+                        .clone(),
+                );
+            }
+            if mods.is_empty() {
+                candidates = derivations.derivations.clone();
+            }
+            println!("checking {} derivation(s) for updates...", candidates.len());
+            let updates = driver.find_updates(&candidates, &store)?;
+            if updates.is_empty() {
+                println!("everything is up to date");
+                return Ok(());
+            }
+            for (pkg_id, version_id) in &updates {
+                let current = derivations
+                    .derivations
+                    .iter()
+                    .find(|d| d.apipkgid.as_ref() == Some(pkg_id));
+                println!(
+                    "{}: {} -> {version_id}",
+                    current.map(|d| d.name.as_str()).unwrap_or(pkg_id),
+                    current
+                        .and_then(|d| d.apiverid.as_deref())
+                        .unwrap_or("unknown")
+                );
+            }
+            if !confirm(&format!("apply {} update(s)?", updates.len()), true)? {
+                return Ok(());
+            }
+            // packages being updated must not count as installed, everything else still does
+            let mut seen: Vec<_> = derivations
+                .get_api_pkg_id_list()
+                .into_iter()
+                .filter(|(id, _)| !updates.iter().any(|(updated, _)| updated == id))
+                .collect();
+            let packages: Vec<_> = updates
+                .iter()
+                .map(|(pkg_id, version_id)| (pkg_id.clone(), Some(version_id.clone())))
+                .collect();
+            let mut updated = driver.get_derivations_for(
+                &packages,
+                &mut seen,
+                OptionalDeps::Skip,
+                true,
+                &store,
+            )?;
+            for derive in &mut updated {
+                derive.backing_file = match derivations
+                    .derivations
+                    .iter()
+                    .find(|d| d.apipkgid.is_some() && d.apipkgid == derive.apipkgid)
+                {
+                    Some(existing) => {
+                        derive.reason = existing.reason;
+                        existing.backing_file.clone()
+                    }
+                    None => {
+                        derive.reason = Reason::Dependency;
+                        format!("{derives}/{}.jade.toml", derive.name)
+                    }
+                };
+            }
+            let updated = resolver::resolve(
+                driver.as_ref(),
+                &derivations.derivations,
+                updated,
+                &derives,
+                &store,
+            )
+            .map_err(|e| format!("{e}\nnothing was updated"))?;
+            util::update_derives(
+                &updated,
+                &format!("{root}/backups"),
+                &derives,
+                &manifest.main.name,
+            )?;
+            println!("updated {} derivation(s)", updated.len());
+        }
+        Commands::List {
+            ref filter,
+            orphans,
+        } => {
             let (manifest, derives) = load_context("./", &args)?;
-            let derivations = Derivations::load_derivations_from_directory(&derives)?;
+            let derivations = Derivations::load_pack(&manifest, &derives)?;
             let name = if let Some(name) = filter {
                 Some(normalize(&name))
             } else {
                 None
             };
-            for derivation in derivations.derivations {
-                if let Some(name) = name.as_ref() {
-                    if derivation.name.contains(name) {
-                        println!("{}\t({})", derivation.name, derivation.backing_file);
+            let orphaned = DependencyGraph::new(&derivations.derivations).orphans();
+            for derivation in &derivations.derivations {
+                if let Some(name) = name.as_ref()
+                    && !derivation.name.contains(name)
+                {
+                    continue;
+                }
+                let is_orphan = orphaned.contains(&derivation.name.as_str());
+                if orphans && !is_orphan {
+                    continue;
+                }
+                let reason = match derivation.reason {
+                    Reason::Explicit => derivation.reason.to_string().green(),
+                    Reason::Dependency if is_orphan => "orphan".yellow(),
+                    Reason::Dependency => derivation.reason.to_string(),
+                };
+                let disabled = if manifest.is_enabled(&derivation.name) {
+                    String::new()
+                } else {
+                    format!(", {}", "disabled".red())
+                };
+                let provenance = match derivation.provenance {
+                    Provenance::Local => String::new(),
+                    _ => format!(" {}", derivation.provenance).cyan(),
+                };
+                println!(
+                    "{}\t[{reason}{disabled}]{provenance}\t({})",
+                    derivation.name, derivation.backing_file
+                );
+            }
+        }
+        Commands::Enable { ref mods } | Commands::Disable { ref mods } => {
+            let enable = matches!(args.command, Commands::Enable { .. });
+            let (mut manifest, derives) = load_context("./", &args)?;
+            let derivations = Derivations::load_pack(&manifest, &derives)?;
+            for name in mods {
+                let derivation = derivations.get_derivation_by_fuzzy_name(&normalize(name))?;
+                manifest.set_enabled(&derivation.name, enable)?;
+                println!(
+                    "{} {}",
+                    if enable { "enabled" } else { "disabled" },
+                    derivation.name
+                );
+            }
+            warn_disabled_dependencies(&manifest, &derivations.derivations);
+        }
+        Commands::Remove { ref mods } => {
+            let (manifest, derives) = load_context("./", &args)?;
+            let mut derivations = Derivations::load_pack(&manifest, &derives)?;
+            let mut removed = Vec::new();
+            for name in mods {
+                removed.push(derivations.get_derivation_by_name(name)?.name.clone());
+            }
+            for name in &removed {
+                let Some(derivation) = derivations.derivations.iter().find(|d| d.name == *name)
+                else {
+                    continue;
+                };
+                match &derivation.provenance {
+                    Provenance::Local => (),
+                    Provenance::Included { pack, .. } => {
+                        return Err(format!(
+                            "{name} is defined by {pack}, use `jade disable {name}` to leave it out of this pack"
+                        ));
+                    }
+                    Provenance::Overridden { pack } => {
+                        return Err(format!(
+                            "{name} is defined by {pack} and overridden in `{}`, delete that file to drop the override or use `jade disable {name}`",
+                            derivation.backing_file
+                        ));
                     }
+                }
+            }
+            let orphaned_before: Vec<String> = DependencyGraph::new(&derivations.derivations)
+                .orphans()
+                .iter()
+                .map(|o| o.to_string())
+                .collect();
+            // mods still needed by something staying in the pack become dependencies instead
+            let mut demoted = Vec::new();
+            let mut kept = Vec::new();
+            for name in &removed {
+                let dependents: Vec<&str> = derivations
+                    .derivations
+                    .iter()
+                    .filter(|d| !removed.contains(&d.name))
+                    .filter(|d| {
+                        d.depends
+                            .iter()
+                            .any(|dep| resolver::dependency_name(dep) == name)
+                    })
+                    .map(|d| d.name.as_str())
+                    .collect();
+                if dependents.is_empty() {
+                    continue;
+                }
+                let explicit = derivations
+                    .derivations
+                    .iter()
+                    .any(|d| d.name == *name && d.reason == Reason::Explicit);
+                if explicit {
+                    println!(
+                        "{name} is still required by {}, keeping it as a dependency",
+                        dependents.join(", ")
+                    );
+                    demoted.push(name.clone());
                 } else {
-                    println!("{}\t({})", derivation.name, derivation.backing_file);
+                    println!(
+                        "{name} is required by {}, not removing it",
+                        dependents.join(", ")
+                    );
                 }
+                kept.push(name.clone());
+            }
+            removed.retain(|name| !kept.contains(name));
+            for derivation in &mut derivations.derivations {
+                if demoted.contains(&derivation.name) {
+                    derivation.reason = Reason::Dependency;
+                }
+            }
+            let remaining: Vec<Derivation> = derivations
+                .derivations
+                .iter()
+                .filter(|d| !removed.contains(&d.name))
+                .cloned()
+                .collect();
+            // only dependencies this removal left unneeded are offered, and only ones this pack owns
+            let orphans: Vec<&str> = DependencyGraph::new(&remaining)
+                .orphans()
+                .into_iter()
+                .filter(|o| !orphaned_before.iter().any(|b| b == o))
+                .filter(|o| {
+                    remaining
+                        .iter()
+                        .any(|d| d.name == *o && d.provenance == Provenance::Local)
+                })
+                .collect();
+            if !orphans.is_empty()
+                && confirm(
+                    &format!(
+                        "remove {} dependencies only the removed mods needed ({})?",
+                        orphans.len(),
+                        orphans.join(", ")
+                    ),
+                    false,
+                )?
+            {
+                removed.extend(orphans.iter().map(|o| o.to_string()));
+            }
+            if removed.is_empty() && demoted.is_empty() {
+                println!("no changes made");
+                return Ok(());
+            }
+            let (to_remove, to_keep): (Vec<&Derivation>, Vec<&Derivation>) = derivations
+                .derivations
+                .iter()
+                .partition(|d| removed.contains(&d.name));
+            for derivation in &to_remove {
+                println!("removing {}", derivation.name);
+            }
+            util::remove_derives(
+                &to_remove,
+                &format!("{root}/backups"),
+                &derives,
+                &manifest.main.name,
+            )?;
+            for derivation in to_keep {
+                if demoted.contains(&derivation.name) {
+                    derivation.write_back()?;
+                }
+            }
+            if !to_remove.is_empty() {
+                println!("removed {} derivation(s)", to_remove.len());
+            }
+        }
+        Commands::Tree { ref modname } => {
+            let (manifest, derives) = load_context("./", &args)?;
+            let derivations = Derivations::load_pack(&manifest, &derives)?;
+            let graph = DependencyGraph::new(&derivations.derivations);
+            if let Some(modname) = modname {
+                let derivation = derivations.get_derivation_by_fuzzy_name(&normalize(modname))?;
+                graph.print_tree(&derivation.name);
+                return Ok(());
+            }
+            let mut roots = graph.roots();
+            let mut reachable = graph.reachable_from(&roots);
+            // members of a cycle nothing else depends on are not reachable from any root
+            for cycle in graph.cycles() {
+                if !cycle.iter().any(|name| reachable.contains(name)) {
+                    roots.push(cycle[0]);
+                    reachable.extend(graph.reachable_from(&[cycle[0]]));
+                }
+                println!(
+                    "{} dependency cycle {}",
+                    "warning:".yellow(),
+                    cycle.join(" -> ")
+                );
+            }
+            for root in roots {
+                graph.print_tree(root);
+            }
+        }
+        Commands::Why { ref modname, limit } => {
+            let (manifest, derives) = load_context("./", &args)?;
+            let derivations = Derivations::load_pack(&manifest, &derives)?;
+            let graph = DependencyGraph::new(&derivations.derivations);
+            let name = &derivations
+                .get_derivation_by_fuzzy_name(&normalize(modname))?
+                .name;
+            let (paths, more) = graph.paths_to(name, limit);
+            if paths.iter().all(|path| path.len() == 1) && !more {
+                println!("{name} is a top-level mod, nothing depends on it");
+                return Ok(());
+            }
+            for path in paths {
+                println!("{}", path.join(" -> "));
+            }
+            if more {
+                println!("more paths not shown, pass --limit to see more than {limit}");
+            }
+        }
+        Commands::Graph { ref format } => {
+            let (manifest, derives) = load_context("./", &args)?;
+            let derivations = Derivations::load_pack(&manifest, &derives)?;
+            let graph = DependencyGraph::new(&derivations.derivations);
+            match format.as_str() {
+                "dot" => print!("{}", graph.to_dot(&manifest.main.name)),
+                _ => return Err(format!("unknown graph format `{format}` (expected dot)")),
             }
         }
         Commands::Version => println!("{}", get_version()),
+        Commands::Cache { ref command } => match command {
+            CacheCommands::Clear => {
+                if let Some(cache) = http_cache::http_cache() {
+                    let removed = cache.clear()?;
+                    println!("removed {removed} cached responses from {}", cache.dir);
+                }
+            }
+        },
+        Commands::ServeStore { ref listen } => serve::serve_store(&store, listen)?,
+        Commands::Store { ref command } => match command {
+            StoreCommands::Verify => {
+                let corrupt = store.find_corrupt()?;
+                if !corrupt.is_empty() {
+                    return Err(format!(
+                        "{} corrupt store entries, run `jade store repair` from a pack using them",
+                        corrupt.len()
+                    ));
+                }
+                println!("store is intact");
+            }
+            StoreCommands::Repair => {
+                let corrupt = store.find_corrupt()?;
+                if corrupt.is_empty() {
+                    println!("store is intact, nothing to repair");
+                    return Ok(());
+                }
+                let db = store.load_db()?;
+                // entries predating the database can still be repaired from the current pack
+                let derivations = match load_context("./", &args) {
+                    Ok((manifest, derives)) => load_pack_derivations(&manifest, &derives)?,
+                    Err(_) => Vec::new(),
+                };
+                let mut unrepaired = Vec::new();
+                for entry in corrupt {
+                    let signature = entry.signature();
+                    let derivation = if let Some(recorded) = db.entries.get(&signature) {
+                        recorded.to_derivation()
+                    } else if let Some(derivation) = derivations
+                        .iter()
+                        .find(|d| d.hash.is_some() && d.generate_hash_signature() == signature)
+                    {
+                        derivation.clone()
+                    } else {
+                        unrepaired.push(signature);
+                        continue;
+                    };
+                    entry.remove()?;
+                    store.realize_derivation(derivation)?;
+                    println!("repaired {signature}");
+                }
+                if !unrepaired.is_empty() {
+                    return Err(format!(
+                        "origin of {} is unknown, run repair from a pack that uses them",
+                        unrepaired.join(", ")
+                    ));
+                }
+            }
+            StoreCommands::Ls => {
+                let db = store.load_db()?;
+                for path in store.entries()? {
+                    let signature = path.signature();
+                    if let Some(entry) = db.entries.get(&signature) {
+                        println!(
+                            "{signature}\t{}\t{} pack(s)",
+                            util::format_size(entry.size),
+                            entry.packs.len()
+                        );
+                    } else {
+                        println!("{signature}\t{}", "untracked".yellow());
+                    }
+                }
+            }
+            StoreCommands::Info { name } => {
+                let db = store.load_db()?;
+                let matches = db.find_by_name(&normalize(name));
+                if matches.is_empty() {
+                    return Err(format!("no store entries match {name}"));
+                }
+                for (signature, entry) in matches {
+                    println!("{}", signature.clone().bold());
+                    println!("\tname: {}", entry.name);
+                    println!("\turl: {}", entry.url);
+                    println!("\tarchive hash: {}", entry.archive_hash);
+                    if let Some(content_hash) = &entry.content_hash {
+                        println!("\tcontent hash: {content_hash}");
+                    }
+                    println!("\tsize: {}", util::format_size(entry.size));
+                    println!("\tadded: {}", entry.added);
+                    println!("\tpacks:");
+                    for pack in &entry.packs {
+                        println!("\t\t{pack}");
+                    }
+                }
+            }
+            StoreCommands::Gc { dry_run, untracked } => {
+                let removed = store.collect_garbage(*dry_run, *untracked)?;
+                println!(
+                    "{} {} unreferenced store entries",
+                    if *dry_run { "found" } else { "removed" },
+                    removed.len()
+                );
+            }
+            StoreCommands::Export { pack, output } => {
+                let (manifest, derives) = load_context(pack.as_deref().unwrap_or("./"), &args)?;
+                store.substituters = manifest.main.substituters.clone().unwrap_or_default();
+                let derivations =
+                    enabled_derivations(&manifest, load_pack_derivations(&manifest, &derives)?);
+                progress().start();
+                let (_, derivations) = store.realize_derivations(derivations)?;
+                store.reference_pack(&manifest.backing_file, None, &derivations)?;
+                closure::export_closure(&store, &manifest, &derivations, output)?;
+            }
+            StoreCommands::Import {
+                closure,
+                pack,
+                strict,
+            } => closure::import_closure(&store, closure, pack.as_deref(), *strict)?,
+        },
     }
     Ok(())
 }
@@ -428,3 +1300,11 @@ fn main() {
 fn get_version() -> String {
     format!("version {}-{}", VERSION, current_platform::CURRENT_PLATFORM)
 }
+
+/// identifies jade to the apis and mirrors it talks to
+fn user_agent() -> String {
+    format!(
+        "Nimrodium/jade/{VERSION} ({}; https://github.com/Nimrodium/jade)",
+        current_platform::CURRENT_PLATFORM
+    )
+}
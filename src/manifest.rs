@@ -1,6 +1,19 @@
 use serde_derive::{Deserialize, Serialize};
-use std::{fs::File, io::Read};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::Read,
+    path::Path,
+};
 use toml::Table;
+
+use colorize::AnsiColor;
+
+use crate::{
+    package::{Derivation, Kind},
+    resolver::dependency_name,
+    util::normalize,
+};
 // #[derive(Deserialize, Serialize)]
 // pub struct Manifest {
 //     pub name: String,
@@ -28,10 +41,21 @@ use toml::Table;
 // }
 #[derive(Deserialize, Serialize)]
 pub struct Manifest {
+    /// packs, as directories or manifest files, this pack builds on
+    pub include: Option<Vec<String>>,
+    /// every pack included directly or through another include, bases first
+    #[serde(skip)]
+    pub included: Vec<IncludedPack>,
     pub main: ManifestMain,
     pub enabled: Option<Table>,
+    pub profiles: Option<BTreeMap<String, Profile>>,
+    /// profile selected with --profile, already applied to main and api_cfg
+    #[serde(skip)]
+    pub profile: Option<Profile>,
     #[serde(flatten)]
     pub api_cfg: Table, // modrinth:{},curseforge:{}
+    #[serde(skip)]
+    pub backing_file: String,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -42,6 +66,128 @@ pub struct ManifestMain {
     pub api: Option<String>,
     pub enable_all: bool,
     pub target: Option<String>,
+    pub deploy: Option<String>,            // symlink, copy or hardlink
+    pub substituters: Option<Vec<String>>, // jade stores (http or local directories) tried before urls
+    pub targets: Option<BTreeMap<String, String>>, // deployment directory per type, e.g. shader = "/path/to/shaderpacks"
+}
+
+pub struct IncludedPack {
+    pub name: String,
+    pub manifest: String,
+    pub derives: String,
+}
+
+/// a variant of the pack, e.g. a lite or server flavor, selected with --profile
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Profile {
+    #[serde(skip)]
+    pub name: String,
+    /// only deploy these derivations and what they depend on
+    pub include: Option<Vec<String>>,
+    pub include_tags: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    pub exclude_tags: Option<Vec<String>>,
+    pub target: Option<String>,
+    #[serde(flatten)]
+    pub api_cfg: Table, // driver settings merged over the pack's, e.g. [profiles.server.modrinth]
+}
+
+impl Profile {
+    fn matches(
+        derivation: &Derivation,
+        names: &Option<Vec<String>>,
+        tags: &Option<Vec<String>>,
+    ) -> bool {
+        names.as_ref().is_some_and(|names| {
+            names
+                .iter()
+                .any(|n| normalize(n) == normalize(&derivation.name))
+        }) || tags
+            .as_ref()
+            .is_some_and(|tags| derivation.tags.iter().any(|t| tags.contains(t)))
+    }
+
+    /// warns about names and tags that match nothing in the pack, most likely typos
+    fn warn_unmatched(&self, derivations: &[Derivation]) {
+        let names = [("include", &self.include), ("exclude", &self.exclude)];
+        for (field, names) in names {
+            for name in names.iter().flatten() {
+                if !derivations
+                    .iter()
+                    .any(|d| normalize(&d.name) == normalize(name))
+                {
+                    println!(
+                        "{} profile {} {field}s {name}, which is not in the pack",
+                        "warning:".yellow(),
+                        self.name
+                    );
+                }
+            }
+        }
+        let tags = [
+            ("include_tags", &self.include_tags),
+            ("exclude_tags", &self.exclude_tags),
+        ];
+        for (field, tags) in tags {
+            for tag in tags.iter().flatten() {
+                if !derivations.iter().any(|d| d.tags.contains(tag)) {
+                    println!(
+                        "{} tag {tag} in {field} of profile {} matches no derivation",
+                        "warning:".yellow(),
+                        self.name
+                    );
+                }
+            }
+        }
+    }
+
+    /// the derivations the profile deploys
+    pub fn select(&self, derivations: Vec<Derivation>) -> Vec<Derivation> {
+        self.warn_unmatched(&derivations);
+        let mut selected: Vec<String> = if self.include.is_some() || self.include_tags.is_some() {
+            derivations
+                .iter()
+                .filter(|d| Self::matches(d, &self.include, &self.include_tags))
+                .map(|d| d.name.clone())
+                .collect()
+        } else {
+            derivations.iter().map(|d| d.name.clone()).collect()
+        };
+        // included derivations bring what they depend on
+        let mut i = 0;
+        while i < selected.len() {
+            if let Some(derivation) = derivations.iter().find(|d| d.name == selected[i]) {
+                for dependency in &derivation.depends {
+                    let name = dependency_name(dependency).to_string();
+                    if !selected.contains(&name) {
+                        selected.push(name);
+                    }
+                }
+            }
+            i += 1;
+        }
+        let (kept, excluded): (Vec<_>, Vec<_>) = derivations
+            .into_iter()
+            .filter(|d| selected.contains(&d.name))
+            .partition(|d| !Self::matches(d, &self.exclude, &self.exclude_tags));
+        for derivation in &kept {
+            for excluded in excluded.iter().filter(|e| {
+                derivation
+                    .depends
+                    .iter()
+                    .any(|d| dependency_name(d) == e.name)
+            }) {
+                println!(
+                    "{} {} requires {}, which profile {} excludes",
+                    "warning:".yellow(),
+                    derivation.name,
+                    excluded.name,
+                    self.name
+                );
+            }
+        }
+        kept
+    }
 }
 
 impl Manifest {
@@ -52,6 +198,8 @@ impl Manifest {
         target: Option<String>,
     ) -> Self {
         Self {
+            include: None,
+            included: Vec::new(),
             main: ManifestMain {
                 name: name.to_string(),
                 pack_version: "0.1".to_string(),
@@ -59,19 +207,232 @@ impl Manifest {
                 api,
                 enable_all: true,
                 target,
+                deploy: None,
+                substituters: None,
+                targets: None,
             },
             enabled: None,
+            profiles: None,
+            profile: None,
             api_cfg: Table::new(),
+            backing_file: String::new(),
         }
     }
     pub fn load(p: &str) -> Result<Self, String> {
+        Self::load_included(p, &mut Vec::new())
+    }
+
+    /// loads a manifest and merges the packs it includes into it, stack holds the manifests
+    /// being loaded to catch include cycles
+    fn load_included(p: &str, stack: &mut Vec<String>) -> Result<Self, String> {
         let mut contents = String::new();
         let mut file = File::open(p).map_err(|e| format!("failed to open manifest `{p}`: {e}"))?;
         file.read_to_string(&mut contents)
             .map_err(|e| format!("failed to read manifest `{p}`: {e} "))?;
-        Ok(
-            toml::from_str(&contents)
-                .map_err(|e| format!("failed to parse manifest `{p}`: {e}"))?,
-        )
+        let mut manifest: Self = toml::from_str(&contents)
+            .map_err(|e| format!("failed to parse manifest `{p}`: {e}"))?;
+        manifest.backing_file = fs::canonicalize(p)
+            .map(|p| p.display().to_string())
+            .unwrap_or(p.to_string());
+        if stack.contains(&manifest.backing_file) {
+            return Err(format!(
+                "include cycle: {} -> {}",
+                stack.join(" -> "),
+                manifest.backing_file
+            ));
+        }
+        let dir = Path::new(&manifest.backing_file)
+            .parent()
+            .unwrap_or(Path::new("."))
+            .to_path_buf();
+        stack.push(manifest.backing_file.clone());
+        for include in manifest.include.clone().unwrap_or_default() {
+            let path = dir.join(&include);
+            let path = if path.is_dir() {
+                path.join(crate::MANIFEST)
+            } else {
+                path
+            };
+            let base = Self::load_included(&path.display().to_string(), stack)
+                .map_err(|e| format!("failed to include `{include}` in `{p}`: {e}"))?;
+            manifest.inherit(base);
+        }
+        stack.pop();
+        Ok(manifest)
+    }
+
+    /// fills in what this manifest leaves unset from an included one
+    fn inherit(&mut self, base: Manifest) {
+        let base_dir = Path::new(&base.backing_file)
+            .parent()
+            .unwrap_or(Path::new("."))
+            .to_path_buf();
+        let derives = match &base.main.derives {
+            Some(derives) => base_dir.join(derives),
+            None => base_dir.join("derives"),
+        };
+        for included in base.included {
+            if !self
+                .included
+                .iter()
+                .any(|i| i.manifest == included.manifest)
+            {
+                self.included.push(included);
+            }
+        }
+        if !self
+            .included
+            .iter()
+            .any(|i| i.manifest == base.backing_file)
+        {
+            self.included.push(IncludedPack {
+                name: base.main.name,
+                manifest: base.backing_file,
+                derives: derives.display().to_string(),
+            });
+        }
+        if self.main.api.is_none() {
+            self.main.api = base.main.api;
+        }
+        if let Some(substituters) = base.main.substituters {
+            let own = self.main.substituters.get_or_insert_with(Vec::new);
+            for substituter in substituters {
+                if !own.contains(&substituter) {
+                    own.push(substituter);
+                }
+            }
+        }
+        if let Some(enabled) = base.enabled {
+            let own = self.enabled.get_or_insert_with(Table::new);
+            for (name, value) in enabled {
+                own.entry(name).or_insert(value);
+            }
+        }
+        if let Some(profiles) = base.profiles {
+            let own = self.profiles.get_or_insert_with(BTreeMap::new);
+            for (name, profile) in profiles {
+                own.entry(name).or_insert(profile);
+            }
+        }
+        // driver settings are merged key by key
+        for (driver, cfg) in base.api_cfg {
+            match (self.api_cfg.get_mut(&driver), cfg) {
+                (Some(toml::Value::Table(own)), toml::Value::Table(cfg)) => {
+                    for (key, value) in cfg {
+                        own.entry(key).or_insert(value);
+                    }
+                }
+                (Some(_), _) => (),
+                (None, cfg) => {
+                    self.api_cfg.insert(driver, cfg);
+                }
+            }
+        }
+    }
+
+    /// applies a profile's overrides and remembers it for selecting derivations
+    pub fn use_profile(&mut self, name: &str) -> Result<(), String> {
+        let profiles = self.profiles.as_ref();
+        let mut profile = profiles
+            .and_then(|profiles| profiles.get(name))
+            .ok_or(format!(
+                "no profile `{name}` in the manifest (available: {})",
+                profiles
+                    .map(|p| p.keys().cloned().collect::<Vec<_>>().join(", "))
+                    .unwrap_or_default()
+            ))?
+            .clone();
+        profile.name = name.to_string();
+        if let Some(target) = &profile.target {
+            self.main.target = Some(target.clone());
+        }
+        for (driver, overrides) in &profile.api_cfg {
+            let overrides = overrides.as_table().ok_or(format!(
+                "profile `{name}` has an unknown setting `{driver}`"
+            ))?;
+            match self
+                .api_cfg
+                .get_mut(driver)
+                .and_then(|cfg| cfg.as_table_mut())
+            {
+                Some(cfg) => cfg.extend(overrides.clone()),
+                None => {
+                    self.api_cfg
+                        .insert(driver.clone(), toml::Value::Table(overrides.clone()));
+                }
+            }
+        }
+        self.profile = Some(profile);
+        Ok(())
+    }
+
+    /// whether a derivation is deployed, enable_all decides for derivations missing from enabled
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.enabled
+            .as_ref()
+            .and_then(|enabled| enabled.get(name))
+            .and_then(|enabled| enabled.as_bool())
+            .unwrap_or(self.main.enable_all)
+    }
+
+    /// records whether a derivation is enabled in the manifest file, leaving the rest of it as is
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        self.enabled
+            .get_or_insert_with(Table::new)
+            .insert(name.to_string(), toml::Value::Boolean(enabled));
+        let p = &self.backing_file;
+        let contents =
+            fs::read_to_string(p).map_err(|e| format!("failed to read manifest `{p}`: {e}"))?;
+        // edited as a document so comments and key order survive
+        let mut document: toml_edit::DocumentMut = contents
+            .parse()
+            .map_err(|e| format!("failed to parse manifest `{p}`: {e}"))?;
+        let section = document
+            .entry("enabled")
+            .or_insert_with(toml_edit::table)
+            .as_table_like_mut()
+            .ok_or(format!("`enabled` in manifest `{p}` is not a table"))?;
+        match section.get_mut(name) {
+            Some(item) => {
+                let decor = item.as_value().map(|v| v.decor().clone());
+                *item = toml_edit::value(enabled);
+                if let (Some(decor), Some(value)) = (decor, item.as_value_mut()) {
+                    *value.decor_mut() = decor;
+                }
+            }
+            None => {
+                section.insert(name, toml_edit::value(enabled));
+            }
+        }
+        fs::write(p, document.to_string())
+            .map_err(|e| format!("failed to write manifest `{p}`: {e}"))
+    }
+
+    /// where derivations of kind are deployed, mods go to target and everything but datapacks
+    /// defaults to its usual directory next to it
+    pub fn target_for(&self, kind: Kind, target: &str) -> Result<String, String> {
+        if let Some(dir) = self
+            .main
+            .targets
+            .as_ref()
+            .and_then(|targets| targets.get(&kind.to_string()))
+        {
+            return Ok(dir.clone());
+        }
+        if kind == Kind::Mod {
+            return Ok(target.to_string());
+        }
+        let Some(dir) = kind.default_dir() else {
+            return Err(format!(
+                "{kind}s only load from a world's datapacks directory, set `targets.{kind}` in the manifest to deploy them"
+            ));
+        };
+        let target = Path::new(target.trim_end_matches(['/', '\\']));
+        Ok(target
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join(dir)
+            .display()
+            .to_string())
     }
 }
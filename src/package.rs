@@ -1,49 +1,268 @@
 use std::{
     collections::HashSet,
+    fmt,
     fs::{self, File},
     hash,
     io::{Read, Write},
-    path::{Path, PathBuf},
+    path::Path,
 };
 
 use serde_derive::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 use crate::{
+    download::Downloader,
+    manifest::Manifest,
+    progress::{self, progress},
     store::{self, Store, StorePath},
-    util::{self, hash_stream, normalize, verify_hash},
+    util::{self, hash_stream, normalize},
 };
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct RawDerivation {
-    url: String,
+    url: Option<String>,
+    urls: Option<Vec<String>>, // mirrors, tried in order after url
     extract: Option<bool>,
     extract_target: Option<String>,
     name: Option<String>,
     file_name: Option<String>,
+    version: Option<String>,
     hash: Option<String>,
+    content_hash: Option<String>,
     depends: Option<Vec<String>>,
+    conflicts: Option<Vec<String>>,
+    embedded: Option<Vec<String>>,
+    side: Option<String>,
+    kind: Option<String>,
+    reason: Option<String>,
     tags: Option<Vec<String>>,
     apipkgid: Option<String>,
     apiverid: Option<String>,
 }
+impl RawDerivation {
+    fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read derivation `{}`: {e}", path.display()))?;
+        toml::from_str(&contents)
+            .map_err(|e| format!("failed to parse derivation `{}`: {e}", path.display()))
+    }
+
+    /// the name from_raw will give the derivation
+    fn name(&self) -> Option<String> {
+        let url = self
+            .url
+            .as_ref()
+            .or(self.urls.as_ref().and_then(|urls| urls.first()));
+        self.name
+            .clone()
+            .or(self.file_name.clone())
+            .or(url.and_then(|url| url.rsplit_once('/').map(|(_, name)| name.to_string())))
+    }
+
+    /// fields set here replace the ones of base
+    fn over(self, base: Self) -> Self {
+        Self {
+            url: self.url.or(base.url),
+            urls: self.urls.or(base.urls),
+            extract: self.extract.or(base.extract),
+            extract_target: self.extract_target.or(base.extract_target),
+            name: self.name.or(base.name),
+            file_name: self.file_name.or(base.file_name),
+            version: self.version.or(base.version),
+            hash: self.hash.or(base.hash),
+            content_hash: self.content_hash.or(base.content_hash),
+            depends: self.depends.or(base.depends),
+            conflicts: self.conflicts.or(base.conflicts),
+            embedded: self.embedded.or(base.embedded),
+            side: self.side.or(base.side),
+            kind: self.kind.or(base.kind),
+            reason: self.reason.or(base.reason),
+            tags: self.tags.or(base.tags),
+            apipkgid: self.apipkgid.or(base.apipkgid),
+            apiverid: self.apiverid.or(base.apiverid),
+        }
+    }
+}
+
 fn is_false(b: &bool) -> bool {
     !b
 }
+
+/// which game installations a derivation belongs in
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Client,
+    Server,
+    Both,
+}
+
+impl Side {
+    pub fn parse(side: &str) -> Result<Self, String> {
+        match side {
+            "client" => Ok(Self::Client),
+            "server" => Ok(Self::Server),
+            "both" => Ok(Self::Both),
+            _ => Err(format!(
+                "unknown side `{side}` (expected client, server or both)"
+            )),
+        }
+    }
+    fn is_both(&self) -> bool {
+        *self == Self::Both
+    }
+    /// whether a derivation for this side is deployed to an installation of side, one of both
+    /// sides like singleplayer gets everything
+    pub fn runs_on(&self, side: Side) -> bool {
+        *self == Self::Both || side == Self::Both || *self == side
+    }
+}
+
+/// what a derivation's artifact is, which decides the directory it is deployed to
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Hash, Debug, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Mod,
+    ResourcePack,
+    Shader,
+    DataPack,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 4] = [Kind::Mod, Kind::ResourcePack, Kind::Shader, Kind::DataPack];
+    pub fn parse(kind: &str) -> Result<Self, String> {
+        match kind {
+            "mod" => Ok(Self::Mod),
+            "resourcepack" => Ok(Self::ResourcePack),
+            "shader" => Ok(Self::Shader),
+            "datapack" => Ok(Self::DataPack),
+            _ => Err(format!(
+                "unknown type `{kind}` (expected mod, resourcepack, shader or datapack)"
+            )),
+        }
+    }
+    fn is_mod(&self) -> bool {
+        *self == Self::Mod
+    }
+    /// directory next to the mods directory the game reads this kind from, datapacks have none
+    /// as they only load from the world they are in
+    pub fn default_dir(&self) -> Option<&'static str> {
+        match self {
+            Self::Mod => Some("mods"),
+            Self::ResourcePack => Some("resourcepacks"),
+            Self::Shader => Some("shaderpacks"),
+            Self::DataPack => None,
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mod => write!(f, "mod"),
+            Self::ResourcePack => write!(f, "resourcepack"),
+            Self::Shader => write!(f, "shader"),
+            Self::DataPack => write!(f, "datapack"),
+        }
+    }
+}
+
+/// why a derivation is in the pack, asked for or pulled in by something that was
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Reason {
+    Explicit,
+    Dependency,
+}
+
+impl Reason {
+    pub fn parse(reason: &str) -> Result<Self, String> {
+        match reason {
+            "explicit" => Ok(Self::Explicit),
+            "dependency" => Ok(Self::Dependency),
+            _ => Err(format!(
+                "unknown reason `{reason}` (expected explicit or dependency)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Explicit => write!(f, "explicit"),
+            Self::Dependency => write!(f, "dependency"),
+        }
+    }
+}
+
+/// which pack defines a derivation
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub enum Provenance {
+    #[default]
+    Local,
+    /// defined by an included pack in file, written back as a local override
+    Included { pack: String, file: String },
+    /// defined by an included pack with some fields replaced by the local pack
+    Overridden { pack: String },
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Local => write!(f, "local"),
+            Self::Included { pack, .. } => write!(f, "from {pack}"),
+            Self::Overridden { pack } => write!(f, "from {pack}, overridden"),
+        }
+    }
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Client => write!(f, "client"),
+            Self::Server => write!(f, "server"),
+            Self::Both => write!(f, "both"),
+        }
+    }
+}
 #[derive(Serialize, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Derivation {
     pub url: String,
+    /// mirrors tried in order when url fails
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub urls: Vec<String>,
     pub name: String,
     pub file_name: String,
+    /// version of the package the artifact is, checked against version requirements in depends
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
     #[serde(skip_serializing_if = "is_false")]
     pub extract: bool,
     pub extract_target: Option<String>,
     pub hash: Option<String>,
+    /// hash of the extracted tree, pinned once it was extracted from the verified archive so
+    /// copies from substituters and closures can be checked against it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub depends: Vec<String>,
+    /// derivations that cannot be in the same pack
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<String>,
+    /// derivations bundled inside this one's artifact
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub embedded: Vec<String>,
+    #[serde(skip_serializing_if = "Side::is_both")]
+    pub side: Side,
+    #[serde(skip_serializing_if = "Kind::is_mod")]
+    pub kind: Kind,
+    pub reason: Reason,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub tags: Vec<String>,
     #[serde(skip_serializing)]
     pub backing_file: String,
+    #[serde(skip)]
+    pub provenance: Provenance,
     pub apipkgid: Option<String>,
     pub apiverid: Option<String>,
 }
@@ -63,34 +282,42 @@ impl Derivation {
     ) -> Self {
         Self {
             url: url.to_string(),
+            urls: Vec::new(),
             name: normalize(name),
             file_name: file_name.to_string(),
+            version: None,
             extract,
             extract_target,
             hash,
+            content_hash: None,
             depends,
+            conflicts: Vec::new(),
+            embedded: Vec::new(),
+            side: Side::Both,
+            kind: Kind::Mod,
+            reason: Reason::Explicit,
             tags,
             backing_file: String::new(),
+            provenance: Provenance::Local,
             apipkgid,
             apiverid,
         }
     }
-    pub fn load(path: &PathBuf) -> Result<Self, String> {
-        let mut contents = String::new();
-        let mut file = File::open(path)
-            .map_err(|e| format!("failed to open derivation `{}`: {e}", path.display()))?;
-        file.read_to_string(&mut contents)
-            .map_err(|e| format!("failed to read derivation `{}`: {e}", path.display()))?;
-        let raw_derivation: RawDerivation = toml::from_str(&contents)
-            .map_err(|e| format!("failed to parse derivation `{}`: {e}", path.display()))?;
-        Ok(Self::from_raw(raw_derivation, &path.display().to_string()))
+    pub fn load(path: &Path) -> Result<Self, String> {
+        Self::from_raw(RawDerivation::load(path)?, &path.display().to_string())
     }
 
-    fn from_raw(derivation: RawDerivation, p: &str) -> Self {
-        let url_extracted_name = derivation
-            .url
+    fn from_raw(derivation: RawDerivation, p: &str) -> Result<Self, String> {
+        let mut urls = derivation.urls.unwrap_or_default();
+        let url = match derivation.url {
+            Some(url) => url,
+            None if !urls.is_empty() => urls.remove(0),
+            None => return Err(format!("derivation `{p}` has neither `url` nor `urls`")),
+        };
+        urls.retain(|mirror| *mirror != url);
+        let url_extracted_name = url
             .rsplit_once('/')
-            .expect("invalid url")
+            .ok_or(format!("derivation `{p}` has an invalid url `{url}`"))?
             .1
             .to_string();
 
@@ -104,8 +331,9 @@ impl Derivation {
             }
         };
 
-        Self {
-            url: derivation.url,
+        Ok(Self {
+            url,
+            urls,
             extract: if let Some(extract) = derivation.extract {
                 extract
             } else {
@@ -120,70 +348,110 @@ impl Derivation {
                 }
             },
             name,
+            version: derivation.version,
             hash: derivation.hash,
+            content_hash: derivation.content_hash,
             depends: if let Some(depends) = derivation.depends {
                 depends
             } else {
                 vec![]
             },
-            // side: if let Some(side) = derivation.side {
-            //     side
-            // } else {
-            //     "both".to_string()
-            // },
+            conflicts: derivation.conflicts.unwrap_or_default(),
+            embedded: derivation.embedded.unwrap_or_default(),
+            side: if let Some(side) = derivation.side {
+                Side::parse(&side).map_err(|e| format!("derivation `{p}` has an {e}"))?
+            } else {
+                Side::Both
+            },
+            kind: if let Some(kind) = derivation.kind {
+                Kind::parse(&kind).map_err(|e| format!("derivation `{p}` has an {e}"))?
+            } else {
+                Kind::Mod
+            },
+            // derivations written by hand are there on purpose
+            reason: if let Some(reason) = derivation.reason {
+                Reason::parse(&reason).map_err(|e| format!("derivation `{p}` has an {e}"))?
+            } else {
+                Reason::Explicit
+            },
             tags: if let Some(tags) = derivation.tags {
                 tags
             } else {
                 vec![]
             },
             backing_file: p.to_string(),
+            provenance: Provenance::Local,
             apipkgid: derivation.apipkgid,
             apiverid: derivation.apiverid,
-        }
+        })
     }
 
     /// blocking, so should be ran from threads
     /// returns downloaded file path in cache
     pub fn download(
         &mut self,
+        downloader: &Downloader,
         tmp: &str,
         prehash: Option<String>,
         hash_format: Option<String>,
     ) -> Result<String, String> {
+        if util::is_offline() {
+            return Err(format!(
+                "{} is not in the store and cannot be downloaded in offline mode",
+                self.name
+            ));
+        }
+        fs::create_dir_all(tmp)
+            .map_err(|e| format!("failed to create staging directory `{tmp}`: {e}"))?;
         let path = format!("{tmp}/{}", self.file_name);
-        println!("downloading {} to {path}", self.url);
-        let mut file = fs::File::create(&path)
-            .map_err(|e| format!("failed to create temporary file `{path}`: {e}"))?;
-
-        let response = reqwest::blocking::get(&self.url)
-            .map_err(|e| format!("failed to download artifact for {}: {e}", self.name))?;
-
-        let bytes = response
-            .bytes()
-            .map_err(|e| format!("failed to read downloaded data for {}: `{e}`", self.name))?;
-
-        if let Some(prehash) = prehash {
-            if let Some(hashfmt) = hash_format {
-                let valid = verify_hash(&bytes, &prehash, &hashfmt)?;
-                if !valid {
-                    return Err(format!("api {hashfmt} checksum failed to validate"));
-                }
-            } else {
+        let prehash = match (prehash, hash_format) {
+            (Some(prehash), Some(hashfmt)) => Some((prehash, hashfmt)),
+            (Some(prehash), None) => {
                 return Err(format!(
                     "prehash provided but hash format missing {prehash}"
                 ));
             }
+            (None, _) => None,
+        };
+        let mut errors = Vec::new();
+        for url in self.sources() {
+            progress::log(&format!("downloading {url} to {path}"));
+            match downloader.fetch(
+                url,
+                &path,
+                prehash.as_ref().map(|(h, f)| (h.as_str(), f.as_str())),
+            ) {
+                Ok(fetched) => {
+                    self.hash = Some(fetched.hash);
+                    return Ok(fetched.path);
+                }
+                Err(e) => {
+                    if self.sources().len() > 1 {
+                        progress::log(&format!("{e}, trying next url"));
+                    }
+                    errors.push(e);
+                }
+            }
         }
-        self.hash = Some(hash_stream(&bytes));
-        file.write_all(&bytes)
-            .map_err(|e| format!("failed to write to disk `{path}`: {e}"))?;
-        Ok(path)
+        Err(format!(
+            "failed to download artifact for {}: {}",
+            self.name,
+            errors.join("; ")
+        ))
+    }
+
+    /// url followed by its mirrors
+    pub fn sources(&self) -> Vec<&str> {
+        let mut sources = vec![self.url.as_str()];
+        sources.extend(self.urls.iter().map(|u| u.as_str()));
+        sources
     }
 
     pub fn extract_package(&self, cache_file_path: &str) -> Result<String, String> {
         // let file = File::open(cache_file_path).map_err(|e|format!("failed to open downloaded archive `{cache_file_path}`: {e}"))?;
         let dest = format!("{cache_file_path}.extracted");
-        println!("extracting {cache_file_path} to {dest}");
+        progress::log(&format!("extracting {cache_file_path} to {dest}"));
+        let _task = progress().task(&format!("extract {}", self.name));
         zip_extensions::zip_extract(
             &Path::new(cache_file_path).to_path_buf(),
             &Path::new(&dest).to_path_buf(),
@@ -200,14 +468,22 @@ impl Derivation {
         let store_path = store.make_package_store_path(self);
         fs::create_dir_all(store_path.to_string())
             .map_err(|e| format!("failed to create store path `{store_path}`: {e}"))?;
-        println!("installing {} to store (`{}`)", self.name, store.store_path);
+        progress::log(&format!(
+            "installing {} to store (`{}`)",
+            self.name, store.store_path
+        ));
         let install_path = store_path.get_artifact();
-        fs::rename(cache_f, install_path).map_err(|e| {
+        fs::rename(cache_f, &install_path).map_err(|e| {
             format!(
                 "failed to install `{}`(`{cache_f}`) to store: {e}",
                 self.name
             )
         })?;
+        store.register_content(&store_path)?;
+        store.record(self, &store_path)?;
+        // artifacts may be symlinked into many packs, nothing should be able to edit them in place
+        store::set_readonly(Path::new(&install_path), true)
+            .map_err(|e| format!("failed to make `{install_path}` read-only: {e}"))?;
         Ok(store_path)
     }
 
@@ -268,6 +544,91 @@ pub fn load_derivations_from_directory(dir: &Path) -> Result<Vec<Derivation>, St
     Ok(derivations)
 }
 
+/// raw derivations of a directory, recursively, with the file each came from
+fn load_raw_from_directory(dir: &Path) -> Result<Vec<(RawDerivation, String)>, String> {
+    let display_dir = dir.display();
+    if !dir.is_dir() {
+        return Err(format!("{display_dir} is not a directory"));
+    }
+    let mut raw = Vec::new();
+    for result in dir
+        .read_dir()
+        .map_err(|e| format!("failed to read directory {display_dir}: {e}"))?
+    {
+        let entry = result.map_err(|e| format!("failed to read directory {display_dir}: {e}"))?;
+        if entry.path().is_dir() {
+            raw.extend(load_raw_from_directory(&entry.path())?);
+        } else if entry.path().is_file() {
+            raw.push((
+                RawDerivation::load(&entry.path())?,
+                entry.path().display().to_string(),
+            ));
+        }
+    }
+    Ok(raw)
+}
+
+/// derivations of a pack merged over the ones of the packs it includes, a local derivation
+/// replaces only the fields it sets of an included one with the same name
+pub fn load_pack_derivations(
+    manifest: &Manifest,
+    derives: &str,
+) -> Result<Vec<Derivation>, String> {
+    let mut merged: Vec<(Option<String>, RawDerivation, String, Provenance)> = Vec::new();
+    for include in &manifest.included {
+        for (raw, file) in load_raw_from_directory(Path::new(&include.derives))? {
+            let name = raw.name();
+            let provenance = Provenance::Included {
+                pack: include.name.clone(),
+                file: file.clone(),
+            };
+            // later includes override earlier ones
+            match merged.iter_mut().find(|m| name.is_some() && m.0 == name) {
+                Some(existing) => {
+                    existing.1 = raw.over(existing.1.clone());
+                    existing.2 = file;
+                    existing.3 = provenance;
+                }
+                None => merged.push((name, raw, file, provenance)),
+            }
+        }
+    }
+    for (raw, file) in load_raw_from_directory(Path::new(derives))? {
+        let name = raw.name();
+        let included = merged
+            .iter_mut()
+            .find(|m| name.is_some() && m.0 == name && matches!(m.3, Provenance::Included { .. }));
+        match included {
+            Some(existing) => {
+                let Provenance::Included { pack, .. } = &existing.3 else {
+                    unreachable!()
+                };
+                let pack = pack.clone();
+                existing.1 = raw.over(existing.1.clone());
+                existing.2 = file;
+                existing.3 = Provenance::Overridden { pack };
+            }
+            None => merged.push((name, raw, file, Provenance::Local)),
+        }
+    }
+    let mut derivations = Vec::new();
+    for (_, raw, file, provenance) in merged {
+        let mut derivation = Derivation::from_raw(raw, &file)?;
+        // writing an included derivation back creates a local override instead of editing the
+        // included pack
+        if let Provenance::Included { .. } = provenance {
+            derivation.backing_file = format!(
+                "{}/{}.jade.toml",
+                derives.trim_end_matches('/'),
+                derivation.name
+            );
+        }
+        derivation.provenance = provenance;
+        derivations.push(derivation);
+    }
+    Ok(derivations)
+}
+
 pub struct Derivations {
     pub derivations: Vec<Derivation>,
 }
@@ -275,28 +636,8 @@ impl Derivations {
     pub fn new(derivations: Vec<Derivation>) -> Self {
         Self { derivations }
     }
-    pub fn load_derivations_from_directory(dir_s: &str) -> Result<Self, String> {
-        let dir = Path::new(dir_s);
-        let display_dir = dir.display();
-        let mut derivations = Vec::<Derivation>::new();
-        if !dir.is_dir() {
-            return Err(format!("{display_dir} is not a directory"));
-        }
-        for result in dir
-            .read_dir()
-            .map_err(|e| format!("failed to read directory {display_dir}: {e}"))?
-        {
-            let entry =
-                result.map_err(|e| format!("failed to read directory {display_dir}: {e}"))?;
-            if entry.path().is_dir() {
-                // enter subdir
-                let sub_derivations = load_derivations_from_directory(&entry.path())?;
-                derivations.extend(sub_derivations);
-            } else if entry.path().is_file() {
-                derivations.push(Derivation::load(&entry.path())?);
-            }
-        }
-        Ok(Self::new(derivations))
+    pub fn load_pack(manifest: &Manifest, derives: &str) -> Result<Self, String> {
+        Ok(Self::new(load_pack_derivations(manifest, derives)?))
     }
     pub fn dedup(&mut self) {
         let mut tmp = HashSet::<Derivation>::new();
@@ -320,6 +661,31 @@ impl Derivations {
             return Err(format!("{name} could not be found"));
         }
     }
+    /// the derivation named exactly name once normalized, close matches are only suggested
+    pub fn get_derivation_by_name(&self, name: &str) -> Result<&Derivation, String> {
+        let normalized_name = normalize(name);
+        if let Some(derivation) = self
+            .derivations
+            .iter()
+            .find(|d| normalize(&d.name) == normalized_name)
+        {
+            return Ok(derivation);
+        }
+        let similar: Vec<&str> = self
+            .derivations
+            .iter()
+            .filter(|d| normalize(&d.name).contains(&normalized_name))
+            .map(|d| d.name.as_str())
+            .collect();
+        if similar.is_empty() {
+            Err(format!("{name} could not be found"))
+        } else {
+            Err(format!(
+                "{name} could not be found, did you mean {}?",
+                similar.join(", ")
+            ))
+        }
+    }
     pub fn get_api_pkg_id_list(&self) -> Vec<(String, Option<String>)> {
         let mut list = Vec::new();
         for derive in &self.derivations {
@@ -0,0 +1,212 @@
+// progress bars for downloads, extraction and deployment, hidden when stdout is not a terminal
+use std::{
+    io::{IsTerminal, stdout},
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use crate::util::format_size;
+
+static PROGRESS: OnceLock<Progress> = OnceLock::new();
+
+/// the process wide progress reporter
+pub fn progress() -> &'static Progress {
+    PROGRESS.get_or_init(Progress::new)
+}
+
+/// prints a line without tearing the bars
+pub fn log(msg: &str) {
+    progress().println(msg);
+}
+
+pub struct Progress {
+    bars: MultiProgress,
+    overall: Mutex<Option<ProgressBar>>,
+    tty: bool,
+    downloaded_bytes: AtomicU64,
+    downloaded_files: AtomicUsize,
+    store_hits: AtomicUsize,
+    started: Mutex<Instant>,
+}
+
+/// a bar for a single file, finished when dropped
+pub struct FileProgress {
+    bar: Option<ProgressBar>,
+}
+
+impl Progress {
+    fn new() -> Self {
+        let tty = stdout().is_terminal();
+        let bars = MultiProgress::with_draw_target(if tty {
+            ProgressDrawTarget::stdout()
+        } else {
+            ProgressDrawTarget::hidden()
+        });
+        Self {
+            bars,
+            overall: Mutex::new(None),
+            tty,
+            downloaded_bytes: AtomicU64::new(0),
+            downloaded_files: AtomicUsize::new(0),
+            store_hits: AtomicUsize::new(0),
+            started: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn println(&self, msg: &str) {
+        if self.tty {
+            let _ = self.bars.println(msg);
+        } else {
+            println!("{msg}");
+        }
+    }
+
+    /// resets the counters used for the summary
+    pub fn start(&self) {
+        *self.started.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+        self.downloaded_bytes.store(0, Ordering::Relaxed);
+        self.downloaded_files.store(0, Ordering::Relaxed);
+        self.store_hits.store(0, Ordering::Relaxed);
+    }
+
+    /// starts a new phase on the overall bar
+    pub fn phase(&self, name: &str, total: usize) {
+        if !self.tty {
+            return;
+        }
+        let mut overall = self.overall.lock().unwrap_or_else(|e| e.into_inner());
+        let bar = overall.get_or_insert_with(|| {
+            let bar = ProgressBar::new(0);
+            bar.set_style(
+                ProgressStyle::with_template("{msg:>12} [{bar:30}] {pos}/{len} ({elapsed})")
+                    .unwrap()
+                    .progress_chars("=> "),
+            );
+            self.bars.insert(0, bar)
+        });
+        bar.reset();
+        bar.set_length(total as u64);
+        bar.set_message(name.to_string());
+    }
+
+    /// marks one item of the current phase as done
+    pub fn advance(&self) {
+        if let Some(bar) = self
+            .overall
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+        {
+            bar.inc(1);
+        }
+    }
+
+    pub fn store_hit(&self) {
+        self.store_hits.fetch_add(1, Ordering::Relaxed);
+        self.advance();
+    }
+
+    /// a byte counting bar for a download, its size is filled in by `FileProgress::set_total`
+    pub fn download(&self, name: &str) -> FileProgress {
+        self.downloaded_files.fetch_add(1, Ordering::Relaxed);
+        if !self.tty {
+            return FileProgress { bar: None };
+        }
+        let bar = ProgressBar::new_spinner();
+        bar.set_style(
+            ProgressStyle::with_template("{msg:>12} {spinner} {bytes} {bytes_per_sec}").unwrap(),
+        );
+        bar.set_message(truncate(name));
+        FileProgress {
+            bar: Some(self.bars.add(bar)),
+        }
+    }
+
+    /// a spinner for work without a measurable size, such as extracting an archive
+    pub fn task(&self, name: &str) -> FileProgress {
+        if !self.tty {
+            return FileProgress { bar: None };
+        }
+        let bar = ProgressBar::new_spinner();
+        bar.set_style(ProgressStyle::with_template("{msg:>12} {spinner} {prefix}").unwrap());
+        bar.set_message(truncate(name));
+        bar.enable_steady_tick(Duration::from_millis(100));
+        FileProgress {
+            bar: Some(self.bars.add(bar)),
+        }
+    }
+
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// clears the bars and prints totals since `start`
+    pub fn summary(&self) {
+        if let Some(bar) = self
+            .overall
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+        {
+            bar.finish_and_clear();
+        }
+        let elapsed = self
+            .started
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .elapsed();
+        println!(
+            "downloaded {} in {} file(s), {} store hit(s), took {:.1}s",
+            format_size(self.downloaded_bytes.load(Ordering::Relaxed)),
+            self.downloaded_files.load(Ordering::Relaxed),
+            self.store_hits.load(Ordering::Relaxed),
+            elapsed.as_secs_f64()
+        );
+    }
+}
+
+impl FileProgress {
+    pub fn set_total(&self, total: u64) {
+        if let Some(bar) = &self.bar {
+            bar.set_style(
+                ProgressStyle::with_template(
+                    "{msg:>12} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} {eta}",
+                )
+                .unwrap()
+                .progress_chars("=> "),
+            );
+            bar.set_length(total);
+        }
+    }
+    pub fn set_position(&self, bytes: u64) {
+        if let Some(bar) = &self.bar {
+            bar.set_position(bytes);
+        }
+    }
+    pub fn inc(&self, bytes: u64) {
+        if let Some(bar) = &self.bar {
+            bar.inc(bytes);
+        }
+    }
+}
+
+impl Drop for FileProgress {
+    fn drop(&mut self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+fn truncate(name: &str) -> String {
+    if name.chars().count() > 24 {
+        format!("{}…", name.chars().take(23).collect::<String>())
+    } else {
+        name.to_string()
+    }
+}
@@ -0,0 +1,566 @@
+// version constraints between derivations and resolving a pack that satisfies all of them
+//
+// a dependency is a derivation name optionally followed by comma separated comparisons,
+// e.g. `fabric-api >=0.90, <0.100`, versions with spaces or commas are quoted (`b ="1.0 beta"`)
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, VecDeque},
+    fmt,
+};
+
+use crate::{
+    api::{APIDriver, OptionalDeps},
+    package::{Derivation, Reason},
+    store::Store,
+};
+
+/// rounds of re-picking versions before the pack is considered unresolvable
+const MAX_ROUNDS: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Eq => write!(f, "="),
+            Op::Ne => write!(f, "!="),
+            Op::Gt => write!(f, ">"),
+            Op::Ge => write!(f, ">="),
+            Op::Lt => write!(f, "<"),
+            Op::Le => write!(f, "<="),
+        }
+    }
+}
+
+/// comparisons a version has to pass all of, empty accepts any version
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct VersionReq {
+    comparators: Vec<(Op, String)>,
+}
+
+impl VersionReq {
+    pub fn parse(req: &str) -> Result<Self, String> {
+        let mut comparators = Vec::new();
+        for comparator in split_comparators(req).into_iter().map(|c| c.trim()) {
+            if comparator.is_empty() || comparator == "*" {
+                continue;
+            }
+            let (op, version) = [
+                (">=", Op::Ge),
+                ("<=", Op::Le),
+                ("!=", Op::Ne),
+                ("==", Op::Eq),
+                (">", Op::Gt),
+                ("<", Op::Lt),
+                ("=", Op::Eq),
+            ]
+            .iter()
+            .find_map(|(prefix, op)| comparator.strip_prefix(prefix).map(|v| (*op, v)))
+            .unwrap_or((Op::Eq, comparator));
+            let version = version.trim();
+            let version = match version.strip_prefix('"') {
+                Some(quoted) => unquote(quoted).ok_or(format!(
+                    "invalid version requirement `{req}` (unclosed quote)"
+                ))?,
+                None if version.is_empty() || version.contains(char::is_whitespace) => {
+                    return Err(format!("invalid version requirement `{req}`"));
+                }
+                None => version.to_string(),
+            };
+            comparators.push((op, version));
+        }
+        Ok(Self { comparators })
+    }
+
+    pub fn is_any(&self) -> bool {
+        self.comparators.is_empty()
+    }
+
+    pub fn matches(&self, version: &str) -> bool {
+        self.comparators.iter().all(|(op, wanted)| {
+            let ordering = compare_versions(version, wanted);
+            match op {
+                Op::Eq => ordering == Ordering::Equal,
+                Op::Ne => ordering != Ordering::Equal,
+                Op::Gt => ordering == Ordering::Greater,
+                Op::Ge => ordering != Ordering::Less,
+                Op::Lt => ordering == Ordering::Less,
+                Op::Le => ordering != Ordering::Greater,
+            }
+        })
+    }
+}
+
+impl fmt::Display for VersionReq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_any() {
+            return write!(f, "*");
+        }
+        let comparators: Vec<String> = self
+            .comparators
+            .iter()
+            .map(|(op, version)| format!("{op}{}", quote_version(version)))
+            .collect();
+        write!(f, "{}", comparators.join(", "))
+    }
+}
+
+/// version as written in a requirement, quoted when it would not parse as is
+pub fn quote_version(version: &str) -> String {
+    let plain = !version.is_empty()
+        && !version.starts_with(['<', '>', '=', '!', '*'])
+        && !version.contains(|c: char| c.is_whitespace() || "\",\\".contains(c));
+    if plain {
+        version.to_string()
+    } else {
+        format!("\"{}\"", version.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// splits a requirement at the commas outside quoted versions
+fn split_comparators(req: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in req.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                parts.push(&req[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    parts.push(&req[start..]);
+    parts
+}
+
+/// the version inside a quoted one, rest starts right after the opening quote
+fn unquote(rest: &str) -> Option<String> {
+    let mut version = String::new();
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => version.push(chars.next()?),
+            '"' => return chars.as_str().trim().is_empty().then_some(version),
+            c => version.push(c),
+        }
+    }
+    None
+}
+
+/// a parsed entry of a derivation's depends
+pub struct Requirement {
+    pub name: String,
+    pub req: VersionReq,
+}
+
+impl Requirement {
+    pub fn parse(dependency: &str) -> Result<Self, String> {
+        let name = dependency_name(dependency);
+        if name.is_empty() {
+            return Err(format!("dependency `{dependency}` has no name"));
+        }
+        let req = VersionReq::parse(&dependency.trim()[name.len()..])
+            .map_err(|e| format!("dependency `{dependency}` has an {e}"))?;
+        Ok(Self {
+            name: name.to_string(),
+            req,
+        })
+    }
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.req.is_any() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} {}", self.name, self.req)
+        }
+    }
+}
+
+/// the derivation name a dependency refers to, without its version requirement
+pub fn dependency_name(dependency: &str) -> &str {
+    let dependency = dependency.trim();
+    let end = dependency
+        .find(|c: char| c.is_whitespace() || "<>=!,".contains(c))
+        .unwrap_or(dependency.len());
+    &dependency[..end]
+}
+
+/// orders loosely semver shaped versions, numeric parts compare as numbers, a pre-release
+/// (`1.0.0-beta`) sorts before its release and build metadata (`+fabric`) is ignored
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn split(version: &str) -> (&str, Option<&str>) {
+        let version = version.trim().trim_start_matches('v');
+        let version = version.split('+').next().unwrap_or(version);
+        match version.split_once('-') {
+            Some((release, pre)) => (release, Some(pre)),
+            None => (version, None),
+        }
+    }
+    let (release_a, pre_a) = split(a);
+    let (release_b, pre_b) = split(b);
+    compare_segments(release_a, release_b, true).then_with(|| match (pre_a, pre_b) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(_), None) => Ordering::Less,
+        (Some(a), Some(b)) => compare_segments(a, b, false),
+    })
+}
+
+/// missing release segments count as 0 so `1.2` equals `1.2.0`
+fn compare_segments(a: &str, b: &str, pad: bool) -> Ordering {
+    let a: Vec<&str> = a.split(['.', '-', '_']).collect();
+    let b: Vec<&str> = b.split(['.', '-', '_']).collect();
+    let is_zero = |s: &str| s.parse::<u64>().is_ok_and(|n| n == 0);
+    for i in 0..a.len().max(b.len()) {
+        let ordering = match (a.get(i), b.get(i)) {
+            (Some(x), Some(y)) => match (x.parse::<u64>(), y.parse::<u64>()) {
+                (Ok(x), Ok(y)) => x.cmp(&y),
+                (Ok(_), Err(_)) => Ordering::Greater,
+                (Err(_), Ok(_)) => Ordering::Less,
+                (Err(_), Err(_)) => x.cmp(y),
+            },
+            (Some(x), None) if pad && is_zero(x) => Ordering::Equal,
+            (None, Some(y)) if pad && is_zero(y) => Ordering::Equal,
+            (Some(_), None) => Ordering::Greater,
+            (None, Some(_)) => Ordering::Less,
+            (None, None) => Ordering::Equal,
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/// something that keeps a pack from being consistent
+pub enum Problem {
+    /// nothing in the pack provides a dependency
+    Missing {
+        name: String,
+        required_by: Vec<String>,
+    },
+    /// the version in the pack does not satisfy every requirement on it, as (requirer, requirement)
+    Unsatisfied {
+        name: String,
+        version: Option<String>,
+        requirements: Vec<(String, VersionReq)>,
+    },
+    /// two derivations that cannot be installed together
+    Conflict { name: String, conflicting: String },
+    /// a dependency of name that cannot be parsed
+    Invalid { name: String, error: String },
+}
+
+impl Problem {
+    /// whether any of names takes part in the problem
+    fn involves(&self, names: &[&str]) -> bool {
+        match self {
+            Problem::Missing { name, required_by } => {
+                names.contains(&name.as_str())
+                    || required_by.iter().any(|r| names.contains(&r.as_str()))
+            }
+            Problem::Unsatisfied {
+                name, requirements, ..
+            } => {
+                names.contains(&name.as_str())
+                    || requirements
+                        .iter()
+                        .any(|(r, _)| names.contains(&r.as_str()))
+            }
+            Problem::Conflict { name, conflicting } => {
+                names.contains(&name.as_str()) || names.contains(&conflicting.as_str())
+            }
+            Problem::Invalid { name, .. } => names.contains(&name.as_str()),
+        }
+    }
+
+    /// describes the problem, naming what pulled each side of it into the pack
+    pub fn explain(&self, pack: &[Derivation]) -> String {
+        match self {
+            Problem::Missing { name, required_by } => format!(
+                "{name} is required by {} but nothing in the pack provides it",
+                required_by
+                    .iter()
+                    .map(|r| chain(r, pack))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Problem::Unsatisfied {
+                name,
+                version,
+                requirements,
+            } => {
+                let mut explanation = format!(
+                    "cannot satisfy {name} ({} in the pack):",
+                    version.as_deref().unwrap_or("unknown version")
+                );
+                for (requirer, req) in requirements {
+                    let holds = version.as_ref().is_some_and(|v| req.matches(v));
+                    explanation.push_str(&format!(
+                        "\n\t{} needs {name} {req}{}",
+                        chain(requirer, pack),
+                        if holds { "" } else { " ✗" }
+                    ));
+                }
+                explanation
+            }
+            Problem::Conflict { name, conflicting } => format!(
+                "{} is incompatible with {}",
+                chain(name, pack),
+                chain(conflicting, pack)
+            ),
+            Problem::Invalid { name, error } => format!("{}: {error}", chain(name, pack)),
+        }
+    }
+}
+
+/// how name ended up in the pack, from a derivation nothing depends on down to name
+fn chain(name: &str, pack: &[Derivation]) -> String {
+    let mut required_by: HashMap<&str, Vec<&str>> = HashMap::new();
+    for derivation in pack {
+        for dependency in &derivation.depends {
+            required_by
+                .entry(dependency_name(dependency))
+                .or_default()
+                .push(&derivation.name);
+        }
+    }
+    let mut parent: HashMap<&str, &str> = HashMap::new();
+    let mut queue = VecDeque::from([name]);
+    let mut root = name;
+    while let Some(current) = queue.pop_front() {
+        let requirers = required_by.get(current).cloned().unwrap_or_default();
+        if requirers.is_empty() {
+            root = current;
+            break;
+        }
+        for requirer in requirers {
+            if requirer != name && !parent.contains_key(requirer) {
+                parent.insert(requirer, current);
+                queue.push_back(requirer);
+            }
+        }
+    }
+    let mut path = vec![root];
+    while let Some(next) = parent.get(path[path.len() - 1]) {
+        path.push(next);
+    }
+    path.join(" -> ")
+}
+
+/// every problem in a pack, an empty list means it is consistent
+pub fn check(pack: &[Derivation]) -> Result<Vec<Problem>, String> {
+    let mut missing: Vec<(String, Vec<String>)> = Vec::new();
+    let mut requirements: HashMap<String, Vec<(String, VersionReq)>> = HashMap::new();
+    let mut invalid = Vec::new();
+    for derivation in pack {
+        for dependency in &derivation.depends {
+            let requirement = match Requirement::parse(dependency) {
+                Ok(requirement) => requirement,
+                Err(error) => {
+                    invalid.push(Problem::Invalid {
+                        name: derivation.name.clone(),
+                        error,
+                    });
+                    continue;
+                }
+            };
+            if !pack.iter().any(|d| d.name == requirement.name) {
+                // an embedded copy satisfies the dependency whatever its version
+                if pack.iter().any(|d| d.embedded.contains(&requirement.name)) {
+                    continue;
+                }
+                match missing
+                    .iter_mut()
+                    .find(|(name, _)| *name == requirement.name)
+                {
+                    Some((_, required_by)) => required_by.push(derivation.name.clone()),
+                    None => missing.push((requirement.name, vec![derivation.name.clone()])),
+                }
+                continue;
+            }
+            if !requirement.req.is_any() {
+                requirements
+                    .entry(requirement.name)
+                    .or_default()
+                    .push((derivation.name.clone(), requirement.req));
+            }
+        }
+    }
+
+    let mut problems: Vec<Problem> = missing
+        .into_iter()
+        .map(|(name, required_by)| Problem::Missing { name, required_by })
+        .chain(invalid)
+        .collect();
+    for derivation in pack {
+        let Some(requirements) = requirements.remove(&derivation.name) else {
+            continue;
+        };
+        let satisfied = derivation
+            .version
+            .as_ref()
+            .is_some_and(|version| requirements.iter().all(|(_, req)| req.matches(version)));
+        if !satisfied {
+            problems.push(Problem::Unsatisfied {
+                name: derivation.name.clone(),
+                version: derivation.version.clone(),
+                requirements,
+            });
+        }
+    }
+    for derivation in pack {
+        for conflict in &derivation.conflicts {
+            let reported = problems.iter().any(|p| {
+                matches!(p, Problem::Conflict { name, conflicting }
+                    if name == conflict && *conflicting == derivation.name)
+            });
+            if !reported && pack.iter().any(|d| d.name == *conflict) {
+                problems.push(Problem::Conflict {
+                    name: derivation.name.clone(),
+                    conflicting: conflict.clone(),
+                });
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// pack with changed written over it, matched by name or backing file
+fn merge(pack: &[Derivation], changed: &[Derivation]) -> Vec<Derivation> {
+    let mut merged: Vec<Derivation> = pack
+        .iter()
+        .filter(|d| {
+            !changed
+                .iter()
+                .any(|c| c.name == d.name || c.backing_file == d.backing_file)
+        })
+        .cloned()
+        .collect();
+    merged.extend(changed.iter().cloned());
+    merged
+}
+
+/// fills in the versions of api managed derivations written before versions were recorded, so
+/// requirements on them are not reported as unmet
+pub fn fill_versions(driver: &dyn APIDriver, pack: &mut [Derivation]) -> Result<(), String> {
+    let ids: Vec<String> = pack
+        .iter()
+        .filter(|d| d.version.is_none())
+        .filter_map(|d| d.apiverid.clone())
+        .collect();
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let numbers = driver.version_numbers(&ids)?;
+    for derivation in pack.iter_mut() {
+        if derivation.version.is_none()
+            && let Some(number) = derivation.apiverid.as_ref().and_then(|id| numbers.get(id))
+        {
+            derivation.version = Some(number.clone());
+        }
+    }
+    Ok(())
+}
+
+/// applies changed to the pack, picking other versions of api managed derivations until every
+/// requirement touching the change holds, returns the derivations that have to be written
+pub fn resolve(
+    driver: &dyn APIDriver,
+    pack: &[Derivation],
+    mut changed: Vec<Derivation>,
+    derives: &str,
+    store: &Store,
+) -> Result<Vec<Derivation>, String> {
+    let mut pack = pack.to_vec();
+    fill_versions(driver, &mut pack)?;
+    let mut touched: Vec<String> = changed.iter().map(|d| d.name.clone()).collect();
+    for _ in 0..MAX_ROUNDS {
+        let merged = merge(&pack, &changed);
+        let names: Vec<&str> = touched.iter().map(|n| n.as_str()).collect();
+        let problems: Vec<Problem> = check(&merged)?
+            .into_iter()
+            .filter(|p| p.involves(&names))
+            .collect();
+        let Some(problem) = problems.first() else {
+            return Ok(changed);
+        };
+        // only unmet version requirements on api managed derivations can be fixed by another pick
+        let Problem::Unsatisfied {
+            name, requirements, ..
+        } = problem
+        else {
+            return Err(problem.explain(&merged));
+        };
+        let Some(pkg_id) = merged
+            .iter()
+            .find(|d| d.name == *name)
+            .and_then(|d| d.apipkgid.clone())
+        else {
+            return Err(problem.explain(&merged));
+        };
+        let versions = driver.list_versions(&pkg_id)?;
+        let Some(version) = versions
+            .iter()
+            .find(|v| requirements.iter().all(|(_, req)| req.matches(&v.number)))
+        else {
+            return Err(format!(
+                "{}\nno version of {name} compatible with the pack satisfies all of them",
+                problem.explain(&merged)
+            ));
+        };
+        println!(
+            "picking {name} {} to satisfy {}",
+            version.number,
+            requirements
+                .iter()
+                .map(|(requirer, req)| format!("{requirer} ({req})"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let mut seen: Vec<(String, Option<String>)> = merged
+            .iter()
+            .filter(|d| d.name != *name)
+            .filter_map(|d| d.apipkgid.clone().map(|id| (id, d.apiverid.clone())))
+            .collect();
+        let fetched = driver.get_derivations_for(
+            &[(pkg_id, Some(version.id.clone()))],
+            &mut seen,
+            OptionalDeps::Skip,
+            true,
+            store,
+        )?;
+        for mut derivation in fetched {
+            let existing = merged
+                .iter()
+                .find(|d| d.apipkgid.is_some() && d.apipkgid == derivation.apipkgid);
+            derivation.backing_file = existing
+                .map(|d| d.backing_file.clone())
+                .unwrap_or(format!("{derives}/{}.jade.toml", derivation.name));
+            derivation.reason = existing.map(|d| d.reason).unwrap_or(Reason::Dependency);
+            changed
+                .retain(|d| d.name != derivation.name && d.backing_file != derivation.backing_file);
+            touched.push(derivation.name.clone());
+            changed.push(derivation);
+        }
+    }
+    Err(format!(
+        "could not resolve the pack within {MAX_ROUNDS} rounds"
+    ))
+}
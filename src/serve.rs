@@ -0,0 +1,192 @@
+// serves the local store as a substituter for other jade instances
+//
+// GET /index                      json list of store entries
+// GET /<hash>-<name>              file artifacts as is, directory artifacts as a tar stream
+//
+// content hashes are not served, clients verify directory artifacts against ones they trust
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    sync::{Arc, Mutex, mpsc},
+    thread,
+    time::Duration,
+};
+
+use serde_json::json;
+
+use crate::{
+    progress,
+    store::{Store, StorePath},
+};
+
+/// connections handled at once, further ones wait in the listen backlog
+const WORKERS: usize = 8;
+/// how long a client may stall before its connection is dropped
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn serve_store(store: &Store, listen: &str) -> Result<(), String> {
+    let listener =
+        TcpListener::bind(listen).map_err(|e| format!("failed to listen on {listen}: {e}"))?;
+    println!("serving {} on http://{listen}", store.store_path);
+    // a fixed pool of workers pulls accepted connections so clients cannot exhaust threads
+    let (sender, receiver) = mpsc::sync_channel::<TcpStream>(WORKERS);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..WORKERS {
+        let store = store.clone();
+        let receiver = receiver.clone();
+        thread::spawn(move || {
+            loop {
+                let next = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                let Ok(stream) = next else {
+                    break;
+                };
+                let peer = stream
+                    .peer_addr()
+                    .map(|a| a.to_string())
+                    .unwrap_or_default();
+                if let Err(e) = handle_connection(&store, stream) {
+                    progress::log(&format!("{peer}: {e}"));
+                }
+            }
+        });
+    }
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                progress::log(&format!("failed to accept connection: {e}"));
+                continue;
+            }
+        };
+        if let Err(e) = stream
+            .set_read_timeout(Some(TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)))
+        {
+            progress::log(&format!("failed to set connection timeout: {e}"));
+            continue;
+        }
+        if sender.send(stream).is_err() {
+            return Err("every connection worker has stopped".to_string());
+        }
+    }
+    Ok(())
+}
+
+fn handle_connection(store: &Store, mut stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // headers are not needed, only drained
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return respond(&mut stream, "400 Bad Request", &[], b"bad request");
+    };
+    let head = match method {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return respond(&mut stream, "405 Method Not Allowed", &[], b""),
+    };
+    progress::log(&format!("{method} {path}"));
+
+    let path = path.trim_start_matches('/');
+    if path == "index" {
+        let body = index(store).to_string();
+        let body = if head { "" } else { body.as_str() };
+        return respond(
+            &mut stream,
+            "200 OK",
+            &[("Content-Type", "application/json")],
+            body.as_bytes(),
+        );
+    }
+    let Some(entry) = find_entry(store, path) else {
+        return respond(&mut stream, "404 Not Found", &[], b"not found");
+    };
+    let artifact = entry.get_artifact();
+
+    if Path::new(&artifact).is_dir() {
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-tar\r\nX-Jade-Artifact: directory\r\nConnection: close\r\n\r\n"
+        )?;
+        if !head {
+            // length is unknown up front, the body ends when the connection closes
+            let mut archive = tar::Builder::new(&mut stream);
+            archive.follow_symlinks(false);
+            archive.append_dir_all(".", &artifact)?;
+            archive.finish()?;
+        }
+        Ok(())
+    } else {
+        let mut file = File::open(&artifact)?;
+        let length = file.metadata()?.len();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {length}\r\nX-Jade-Artifact: file\r\nConnection: close\r\n\r\n"
+        )?;
+        if !head {
+            io::copy(&mut file, &mut stream)?;
+        }
+        Ok(())
+    }
+}
+
+fn find_entry(store: &Store, signature: &str) -> Option<StorePath> {
+    if signature.is_empty()
+        || signature.contains("..")
+        || signature.contains('/')
+        || signature.starts_with('.')
+    {
+        return None;
+    }
+    store
+        .entries()
+        .ok()?
+        .into_iter()
+        .find(|entry| entry.signature() == signature)
+}
+
+fn index(store: &Store) -> serde_json::Value {
+    let db = store.load_db().unwrap_or_default();
+    let entries = store.entries().unwrap_or_default();
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            let signature = entry.signature();
+            let recorded = db.entries.get(&signature);
+            json!({
+                "signature": signature,
+                "directory": Path::new(&entry.get_artifact()).is_dir(),
+                "size": recorded.map(|r| r.size),
+                "name": recorded.map(|r| r.name.clone()),
+            })
+        })
+        .collect();
+    json!({ "entries": entries })
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> io::Result<()> {
+    write!(stream, "HTTP/1.1 {status}\r\n")?;
+    for (name, value) in headers {
+        write!(stream, "{name}: {value}\r\n")?;
+    }
+    write!(
+        stream,
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(body)
+}
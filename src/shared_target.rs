@@ -0,0 +1,185 @@
+// deployments into directories the game and the user also write to, e.g. resourcepacks or
+// shaderpacks, which are updated file by file instead of swapped as a whole
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    generation::is_store_deployment,
+    progress,
+    store::{DeployMode, StorePath},
+};
+
+/// a target directory jade shares with everything else, only the entries recorded in
+/// `<target parent>/.jade/<target name>/placed` belong to jade
+pub struct SharedTarget {
+    target: PathBuf,
+    record: PathBuf,
+    placed: BTreeSet<String>,
+}
+
+impl SharedTarget {
+    pub fn open(target: &str, store_path: &str) -> Result<Self, String> {
+        let target = Path::new(target.trim_end_matches(['/', '\\'])).to_path_buf();
+        let target_name = target
+            .file_name()
+            .ok_or(format!(
+                "target `{}` has no directory name",
+                target.display()
+            ))?
+            .to_string_lossy()
+            .to_string();
+        let parent = match target.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let jade_dir = parent.join(".jade").join(&target_name);
+        let record = jade_dir.join("placed");
+        let placed = if record.exists() {
+            fs::read_to_string(&record)
+                .map_err(|e| format!("failed to read `{}`: {e}", record.display()))?
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect()
+        } else {
+            BTreeSet::new()
+        };
+        let mut shared = Self {
+            target,
+            record,
+            placed,
+        };
+        shared.leave_generations(&jade_dir, store_path)?;
+        Ok(shared)
+    }
+
+    /// whether jade ever deployed anything to this target
+    pub fn is_used(&self) -> bool {
+        self.record.exists()
+    }
+
+    /// places paths in the target, removing what earlier composes placed that is no longer
+    /// wanted, entries jade did not place are never touched
+    pub fn deploy(
+        &mut self,
+        paths: &[&StorePath],
+        mode: DeployMode,
+        store_path: &str,
+    ) -> Result<(), String> {
+        let wanted: BTreeSet<String> = paths.iter().map(|path| path.name().to_string()).collect();
+        let mut store_files = None;
+        let foreign: Vec<&String> = wanted
+            .iter()
+            .filter(|name| {
+                let dest = self.target.join(name);
+                fs::symlink_metadata(&dest).is_ok()
+                    && !self.placed.contains(*name)
+                    && !is_store_deployment(&dest, Path::new(store_path), &mut store_files)
+            })
+            .collect();
+        if !foreign.is_empty() {
+            return Err(format!(
+                "`{}` already holds {} jade did not place there, move them out of the way so jade can deploy there",
+                self.target.display(),
+                foreign
+                    .iter()
+                    .map(|name| name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        fs::create_dir_all(&self.target)
+            .map_err(|e| format!("failed to create target `{}`: {e}", self.target.display()))?;
+        // recorded before placing anything so an interrupted deploy leaves nothing untracked
+        self.placed.extend(wanted.iter().cloned());
+        self.save()?;
+        for name in self.placed.difference(&wanted) {
+            let dest = self.target.join(name);
+            let removed = match fs::symlink_metadata(&dest) {
+                Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&dest),
+                Ok(_) => fs::remove_file(&dest),
+                Err(_) => Ok(()),
+            };
+            removed.map_err(|e| format!("failed to remove `{}`: {e}", dest.display()))?;
+            progress::log(&format!("removed {}", dest.display()));
+        }
+        let target = self.target.display().to_string();
+        for path in paths {
+            path.install_to(&target, mode)?;
+        }
+        self.placed = wanted;
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), String> {
+        if let Some(dir) = self.record.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("failed to create `{}`: {e}", dir.display()))?;
+        }
+        let contents: String = self.placed.iter().map(|name| format!("{name}\n")).collect();
+        fs::write(&self.record, contents)
+            .map_err(|e| format!("failed to write `{}`: {e}", self.record.display()))
+    }
+
+    /// turns a target deployed as whole generations back into a plain directory, keeping what
+    /// the user or game added to the current generation
+    fn leave_generations(&mut self, jade_dir: &Path, store_path: &str) -> Result<(), String> {
+        let generations: Vec<PathBuf> = match fs::read_dir(jade_dir) {
+            Ok(entries) => entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.file_name()
+                        .is_some_and(|name| name.to_string_lossy().starts_with("gen-"))
+                })
+                .collect(),
+            Err(_) => return Ok(()),
+        };
+        if generations.is_empty() {
+            return Ok(());
+        }
+        let is_link = fs::symlink_metadata(&self.target).is_ok_and(|m| m.file_type().is_symlink());
+        if is_link {
+            let current = fs::canonicalize(&self.target).map_err(|e| {
+                format!("failed to resolve target `{}`: {e}", self.target.display())
+            })?;
+            fs::remove_file(&self.target).map_err(|e| {
+                format!(
+                    "failed to remove generation link `{}`: {e}",
+                    self.target.display()
+                )
+            })?;
+            fs::rename(&current, &self.target).map_err(|e| {
+                format!(
+                    "failed to move `{}` to `{}`: {e}",
+                    current.display(),
+                    self.target.display()
+                )
+            })?;
+        }
+        // what jade deployed is still recognisable, everything else was added later
+        let mut store_files = None;
+        if let Ok(entries) = fs::read_dir(&self.target) {
+            for entry in entries.flatten() {
+                if is_store_deployment(&entry.path(), Path::new(store_path), &mut store_files) {
+                    self.placed
+                        .insert(entry.file_name().to_string_lossy().to_string());
+                }
+            }
+        }
+        self.save()?;
+        for generation in generations {
+            if generation.exists() {
+                let _ = fs::remove_dir_all(&generation);
+            }
+        }
+        progress::log(&format!(
+            "moved {} out of generations, jade now only manages the files it places there",
+            self.target.display()
+        ));
+        Ok(())
+    }
+}
@@ -1,22 +1,191 @@
 use std::{
+    collections::BTreeSet,
     fmt::{Debug, Display},
-    fs,
+    fs, io,
     path::Path,
-    sync::{Arc, mpsc},
-    thread::{self, JoinHandle},
+    sync::{Arc, Mutex, mpsc},
+    thread,
 };
 
-use crate::package::{Derivation, Derivations};
+use colorize::AnsiColor;
+
+use crate::{
+    download::Downloader,
+    package::{Derivation, Derivations},
+    progress::{self, progress},
+    store_db::{StoreDb, StoreEntry},
+    util::{self, disk_size, hash_directory, hash_stream},
+};
+
+/// maps content hashes of directory artifacts to the store entry first holding that tree
+const CONTENT_INDEX: &str = ".content";
 #[derive(Clone)]
 pub struct Store {
     pub store_path: String,
     pub temp: String,
+    pub downloader: Arc<Downloader>,
+    /// other stores queried by `<hash>-<name>` before a derivation's url
+    pub substituters: Vec<String>,
+    /// serializes database updates from realization threads
+    db_lock: Arc<Mutex<()>>,
 }
 impl Store {
-    pub fn new(store_path: &str, temp: &str) -> Self {
+    pub fn new(store_path: &str, temp: &str, downloader: Downloader) -> Self {
         Self {
             store_path: store_path.to_string(),
             temp: temp.to_string(),
+            downloader: Arc::new(downloader),
+            substituters: Vec::new(),
+            db_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn load_db(&self) -> Result<StoreDb, String> {
+        StoreDb::load(&self.store_path)
+    }
+    /// loads the database, applies f and writes it back
+    pub fn update_db<T>(&self, f: impl FnOnce(&mut StoreDb) -> T) -> Result<T, String> {
+        let _guard = self.db_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut db = self.load_db()?;
+        let result = f(&mut db);
+        db.save(&self.store_path)?;
+        Ok(result)
+    }
+    /// records a freshly installed store path in the database
+    pub fn record(&self, derivation: &Derivation, store_path: &StorePath) -> Result<(), String> {
+        let entry = StoreEntry::new(
+            derivation,
+            store_path.content_hash(),
+            disk_size(Path::new(&store_path.get_artifact())),
+        );
+        self.update_db(|db| db.record(&store_path.signature(), entry))
+    }
+    /// records the store paths of derivations, and marks them as the ones the pack with manifest
+    /// `pack` deploys to `target`
+    pub fn reference_pack(
+        &self,
+        pack: &str,
+        target: Option<&str>,
+        derivations: &[Derivation],
+    ) -> Result<(), String> {
+        let mut realized = Vec::new();
+        let mut signatures = BTreeSet::new();
+        for derivation in derivations {
+            if let Some(store_path) = self.is_package_in_store(derivation) {
+                signatures.insert(store_path.signature());
+                realized.push((derivation, store_path));
+            }
+        }
+        self.update_db(|db| {
+            // entries added before the database existed
+            for (derivation, store_path) in realized {
+                if !db.entries.contains_key(&store_path.signature()) {
+                    let entry = StoreEntry::new(
+                        derivation,
+                        store_path.content_hash(),
+                        disk_size(Path::new(&store_path.get_artifact())),
+                    );
+                    db.record(&store_path.signature(), entry);
+                }
+            }
+            if let Some(target) = target {
+                db.set_pack_references(pack, target, &signatures);
+            }
+        })
+    }
+    /// removes every tracked entry no existing pack references, returns the removed signatures
+    ///
+    /// entries missing from the database are only removed with untracked, they may predate it
+    /// and still be deployed somewhere
+    pub fn collect_garbage(&self, dry_run: bool, untracked: bool) -> Result<Vec<String>, String> {
+        let (pruned, garbage) = self.update_db(|db| {
+            let pruned = db.prune_missing_packs();
+            let garbage: Vec<String> = db
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.packs.is_empty())
+                .map(|(signature, _)| signature.clone())
+                .collect();
+            (pruned, garbage)
+        })?;
+        for pack in pruned {
+            println!("forgetting {pack} (manifest or target no longer exists)");
+        }
+        // entries left behind by an interrupted install or a lost database are never referenced
+        let tracked = self.load_db()?.entries;
+        let found: Vec<String> = self
+            .entries()?
+            .iter()
+            .map(|entry| entry.signature())
+            .filter(|signature| !tracked.contains_key(signature))
+            .collect();
+        let untracked = if untracked {
+            found
+        } else {
+            if !found.is_empty() {
+                println!(
+                    "{} entries are not in the store database, pass --untracked to remove them too",
+                    found.len()
+                );
+            }
+            Vec::new()
+        };
+        let mut removed = Vec::new();
+        for signature in untracked {
+            if dry_run {
+                println!("would remove {signature} (untracked)");
+            } else {
+                println!("removing {signature} (untracked)");
+                let (hash, name) = signature.split_once('-').unwrap_or((&signature, ""));
+                StorePath::new(&format!("{}/{signature}", self.store_path), name, hash).remove()?;
+                self.forget_content(&signature);
+            }
+            removed.push(signature);
+        }
+        for signature in garbage {
+            let (hash, name) = signature.split_once('-').unwrap_or((&signature, ""));
+            let store_path =
+                StorePath::new(&format!("{}/{signature}", self.store_path), name, hash);
+            if dry_run {
+                println!("would remove {signature}");
+            } else {
+                println!("removing {signature}");
+                if Path::new(&store_path.to_string()).exists() {
+                    store_path.remove()?;
+                }
+                self.update_db(|db| db.entries.remove(&signature))?;
+                self.forget_content(&signature);
+            }
+            removed.push(signature);
+        }
+        Ok(removed)
+    }
+    /// repoints content index entries of a removed store entry at another entry sharing the
+    /// tree, dropping them once no entry holds it anymore
+    fn forget_content(&self, signature: &str) {
+        let index_dir = format!("{}/{CONTENT_INDEX}", self.store_path);
+        let Ok(entries) = Path::new(&index_dir).read_dir() else {
+            return;
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            if !fs::read_to_string(entry.path()).is_ok_and(|s| s.trim() == signature) {
+                continue;
+            }
+            let content_hash = entry.file_name().to_string_lossy().to_string();
+            let holder = self.entries().ok().and_then(|store_paths| {
+                store_paths.into_iter().find(|store_path| {
+                    store_path.signature() != signature
+                        && store_path.content_hash().as_deref() == Some(content_hash.as_str())
+                })
+            });
+            match holder {
+                Some(holder) => {
+                    let _ = fs::write(entry.path(), holder.signature());
+                }
+                None => {
+                    let _ = fs::remove_file(entry.path());
+                }
+            }
         }
     }
 
@@ -56,10 +225,36 @@ impl Store {
         if let Some(path) = self.is_package_in_store(&derivation) {
             Ok((path, derivation))
         } else {
+            if let Some(path) = self.substitute(&derivation) {
+                return Ok((path, derivation));
+            }
             let cache_file = {
-                let path = derivation.download(&self.temp, None, None)?;
+                // a pinned hash is verified while downloading so a bad mirror falls through
+                let hash_format = derivation.hash.as_ref().map(|_| "nix".to_string());
+                let path = derivation.download(
+                    &self.downloader,
+                    &self.temp,
+                    derivation.hash.clone(),
+                    hash_format,
+                )?;
+                // unpinned derivations only learn their store path once downloaded
+                if let Some(store_path) = self.is_package_in_store(&derivation) {
+                    let _ = fs::remove_file(&path);
+                    return Ok((store_path, derivation));
+                }
                 if derivation.extract {
-                    derivation.extract_package(&path)?
+                    let extracted = derivation.extract_package(&path)?;
+                    if let Some(expected) = &derivation.content_hash {
+                        let actual = hash_directory(Path::new(&extracted))?;
+                        if actual != *expected {
+                            let _ = remove_fs_entity(&extracted);
+                            return Err(format!(
+                                "content hash of {} failed to validate (expected {expected}, got {actual})",
+                                derivation.name
+                            ));
+                        }
+                    }
+                    extracted
                 } else {
                     path
                 }
@@ -67,6 +262,133 @@ impl Store {
             Ok((derivation.install_to_store(&self, &cache_file)?, derivation))
         }
     }
+    /// tries to copy a pinned derivation's store path from a substituter before going to its url
+    pub fn substitute(&self, derivation: &Derivation) -> Option<StorePath> {
+        derivation.hash.as_ref()?;
+        for substituter in &self.substituters {
+            if util::is_offline() && is_remote(substituter) {
+                continue;
+            }
+            match self.try_substitute(substituter, derivation) {
+                Ok(Some(path)) => return Some(path),
+                Ok(None) => continue,
+                Err(e) => progress::log(
+                    &format!(
+                        "warning: substituter {substituter} failed for {}: {e}",
+                        derivation.name
+                    )
+                    .yellow(),
+                ),
+            }
+        }
+        None
+    }
+    fn try_substitute(
+        &self,
+        substituter: &str,
+        derivation: &Derivation,
+    ) -> Result<Option<StorePath>, String> {
+        let signature = derivation.generate_hash_signature();
+        let hash = derivation.hash.clone().unwrap_or_default();
+        fs::create_dir_all(&self.temp)
+            .map_err(|e| format!("failed to create staging directory `{}`: {e}", self.temp))?;
+        let staged = format!("{}/{signature}", self.temp);
+        if is_remote(substituter) {
+            let url = format!("{}/{signature}", substituter.trim_end_matches('/'));
+            if !self.downloader.exists(&url) {
+                return Ok(None);
+            }
+            if derivation.extract {
+                // directory artifacts arrive as a tar stream, the substituter's own content hash
+                // proves nothing so only a hash known here can vouch for the tree
+                let Some(content_hash) = self.expected_content_hash(derivation)? else {
+                    progress::log(&format!(
+                        "{} has no pinned content hash, not substituting it from {substituter}",
+                        derivation.name
+                    ));
+                    return Ok(None);
+                };
+                let archive = format!("{staged}.tar");
+                self.downloader.fetch(&url, &archive, None)?;
+                if Path::new(&staged).exists() {
+                    remove_fs_entity(&staged)?;
+                }
+                let file = fs::File::open(&archive)
+                    .map_err(|e| format!("failed to open `{archive}`: {e}"))?;
+                tar::Archive::new(file)
+                    .unpack(&staged)
+                    .map_err(|e| format!("failed to unpack `{archive}`: {e}"))?;
+                let _ = fs::remove_file(&archive);
+                let actual = hash_directory(Path::new(&staged))?;
+                if actual != content_hash {
+                    let _ = remove_fs_entity(&staged);
+                    return Err(format!(
+                        "content hash of {url} failed to validate (expected {content_hash}, got {actual})"
+                    ));
+                }
+            } else {
+                self.downloader.fetch(&url, &staged, Some((&hash, "nix")))?;
+            }
+        } else {
+            let root = substituter.strip_prefix("file://").unwrap_or(substituter);
+            let entry =
+                StorePath::new(&format!("{root}/{signature}"), &derivation.file_name, &hash);
+            let artifact = entry.get_artifact();
+            if !Path::new(&artifact).exists() {
+                return Ok(None);
+            }
+            if Path::new(&artifact).is_dir() {
+                let Some(content_hash) = self.expected_content_hash(derivation)? else {
+                    progress::log(&format!(
+                        "{} has no pinned content hash, not substituting it from {substituter}",
+                        derivation.name
+                    ));
+                    return Ok(None);
+                };
+                let actual = hash_directory(Path::new(&artifact))?;
+                if actual != content_hash {
+                    return Err(format!(
+                        "content hash of `{artifact}` failed to validate (expected {content_hash}, got {actual})"
+                    ));
+                }
+            } else {
+                match entry.verify()? {
+                    Integrity::Valid => (),
+                    Integrity::Unverifiable(reason) => {
+                        return Err(format!("cannot verify `{artifact}`: {reason}"));
+                    }
+                    Integrity::Corrupt { .. } | Integrity::Missing => {
+                        return Err(format!("`{artifact}` is corrupt"));
+                    }
+                }
+            }
+            if Path::new(&staged).exists() {
+                remove_fs_entity(&staged)?;
+            }
+            if Path::new(&artifact).is_dir() {
+                copy_dir::copy_dir(&artifact, &staged)
+                    .map_err(|e| format!("failed to copy `{artifact}`: {e}"))?;
+            } else {
+                fs::copy(&artifact, &staged)
+                    .map_err(|e| format!("failed to copy `{artifact}`: {e}"))?;
+            }
+            let _ = set_readonly(Path::new(&staged), false);
+        }
+        progress::log(&format!("substituting {signature} from {substituter}"));
+        Ok(Some(derivation.install_to_store(self, &staged)?))
+    }
+    /// content hash the tree of derivation must have, pinned in the derivation or recorded when
+    /// this store extracted it from the verified archive
+    pub fn expected_content_hash(&self, derivation: &Derivation) -> Result<Option<String>, String> {
+        if derivation.content_hash.is_some() {
+            return Ok(derivation.content_hash.clone());
+        }
+        Ok(self
+            .load_db()?
+            .entries
+            .get(&derivation.generate_hash_signature())
+            .and_then(|entry| entry.content_hash.clone()))
+    }
     /// fetches derivation store paths, executing derivation if not present
     pub fn realize_derivations(
         &self,
@@ -75,30 +397,184 @@ impl Store {
         let (sender, receiver) = mpsc::channel();
         let mut realized = Vec::<StorePath>::new();
         let mut new_derivations = Vec::<Derivation>::new();
-        // let mut handles: Vec<JoinHandle<()>> = Vec::new();
+        let mut missing = Vec::new();
+        progress().phase("realizing", derivations.len());
         for derivation in derivations {
             if let Some(store_path) = self.is_package_in_store(&derivation) {
-                println!("package already in store {store_path}");
+                progress::log(&format!("package already in store {store_path}"));
+                progress().store_hit();
+                realized.push(store_path);
+                new_derivations.push(derivation);
+            } else {
+                missing.push(derivation);
+            }
+        }
+        if util::is_offline() {
+            // local substituters are the only source left, anything else cannot be realized
+            let db = self.load_db()?;
+            let mut unavailable = Vec::new();
+            for mut derivation in missing.drain(..) {
+                // unpinned derivations can still be found through what was recorded when they were fetched
+                if derivation.hash.is_none()
+                    && let Some(entry) = db
+                        .entries
+                        .values()
+                        .find(|e| e.name == derivation.name && e.url == derivation.url)
+                {
+                    derivation.hash = Some(entry.archive_hash.clone());
+                }
+                let store_path = if let Some(store_path) = self.is_package_in_store(&derivation) {
+                    progress().store_hit();
+                    store_path
+                } else if let Some(store_path) = self.substitute(&derivation) {
+                    progress().advance();
+                    store_path
+                } else {
+                    unavailable.push(derivation.name);
+                    continue;
+                };
                 realized.push(store_path);
                 new_derivations.push(derivation);
-                continue;
             }
+            if !unavailable.is_empty() {
+                return Err(format!(
+                    "offline mode: {} derivation(s) missing from the store: {}",
+                    unavailable.len(),
+                    unavailable.join(", ")
+                ));
+            }
+        }
+        // a fixed pool of workers pulls from the queue so at most `jobs` downloads run at once
+        let workers = self.downloader.jobs().min(missing.len());
+        let queue = Arc::new(Mutex::new(missing.into_iter()));
+        for _ in 0..workers {
             let cloned_self = self.clone();
             let cloned_sender = sender.clone();
+            let queue = queue.clone();
             thread::spawn(move || {
-                let result = cloned_self.realize_derivation(derivation);
-                cloned_sender.send(result).unwrap();
+                loop {
+                    let next = queue.lock().unwrap_or_else(|e| e.into_inner()).next();
+                    let Some(derivation) = next else {
+                        break;
+                    };
+                    let result = cloned_self.realize_derivation(derivation);
+                    // the receiver is gone once any realization failed, stop taking work
+                    if cloned_sender.send(result).is_err() {
+                        break;
+                    }
+                }
             });
         }
         drop(sender);
 
         for recieved in receiver {
             let (store_path, new_derivation) = recieved?;
+            progress().advance();
             realized.push(store_path);
             new_derivations.push(new_derivation);
         }
+        // trees extracted or verified here can be pinned for substituters and closures
+        for (store_path, derivation) in realized.iter().zip(new_derivations.iter_mut()) {
+            if derivation.extract && derivation.content_hash.is_none() {
+                derivation.content_hash = store_path.content_hash();
+            }
+        }
         Ok((realized, new_derivations))
     }
+    /// records the content hash of a directory artifact and, if an identical tree is already in
+    /// the store, replaces the new copy with hardlinks to it
+    pub fn register_content(&self, store_path: &StorePath) -> Result<(), String> {
+        let artifact = store_path.get_artifact();
+        if !Path::new(&artifact).is_dir() {
+            return Ok(());
+        }
+        let content_hash = hash_directory(Path::new(&artifact))?;
+        fs::write(store_path.content_hash_file(), &content_hash)
+            .map_err(|e| format!("failed to record content hash for `{store_path}`: {e}"))?;
+
+        let index_dir = format!("{}/{CONTENT_INDEX}", self.store_path);
+        fs::create_dir_all(&index_dir)
+            .map_err(|e| format!("failed to create content index `{index_dir}`: {e}"))?;
+        let index_entry = format!("{index_dir}/{content_hash}");
+        let signature = store_path.signature();
+        if let Ok(existing) = fs::read_to_string(&index_entry) {
+            let existing = existing.trim();
+            let existing_artifact = format!("{}/{existing}/artifact", self.store_path);
+            if existing != signature
+                && Path::new(&existing_artifact).is_dir()
+                && hash_directory(Path::new(&existing_artifact))? == content_hash
+            {
+                progress::log(&format!(
+                    "{signature} is identical to {existing}, deduplicating"
+                ));
+                fs::remove_dir_all(&artifact)
+                    .map_err(|e| format!("failed to remove duplicate tree `{artifact}`: {e}"))?;
+                hardlink_tree(Path::new(&existing_artifact), Path::new(&artifact)).map_err(
+                    |e| format!("failed to link `{existing_artifact}` to `{artifact}`: {e}"),
+                )?;
+                return Ok(());
+            }
+        }
+        fs::write(&index_entry, &signature)
+            .map_err(|e| format!("failed to write content index entry `{index_entry}`: {e}"))
+    }
+    /// lists every entry in the store
+    pub fn entries(&self) -> Result<Vec<StorePath>, String> {
+        let mut entries = Vec::new();
+        let dir = Path::new(&self.store_path);
+        if !dir.exists() {
+            return Ok(entries);
+        }
+        for result in dir
+            .read_dir()
+            .map_err(|e| format!("failed to read store `{}`: {e}", self.store_path))?
+        {
+            let entry =
+                result.map_err(|e| format!("failed to read store `{}`: {e}", self.store_path))?;
+            let signature = entry.file_name().to_string_lossy().to_string();
+            if !entry.path().is_dir() || signature.starts_with('.') {
+                continue;
+            }
+            if let Some((hash, name)) = signature.split_once('-') {
+                entries.push(StorePath::new(
+                    &format!("{}/{signature}", self.store_path),
+                    name,
+                    hash,
+                ));
+            }
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+    /// verifies every store entry, printing its status, and returns the ones that failed
+    pub fn find_corrupt(&self) -> Result<Vec<StorePath>, String> {
+        let mut corrupt = Vec::new();
+        for entry in self.entries()? {
+            match entry.verify()? {
+                Integrity::Valid => println!("{} {}", "ok".green(), entry.signature()),
+                Integrity::Corrupt { actual } => {
+                    println!(
+                        "{} {} (hashes to {actual})",
+                        "corrupt".red(),
+                        entry.signature()
+                    );
+                    corrupt.push(entry);
+                }
+                Integrity::Missing => {
+                    println!(
+                        "{} {} (artifact missing)",
+                        "corrupt".red(),
+                        entry.signature()
+                    );
+                    corrupt.push(entry);
+                }
+                Integrity::Unverifiable(reason) => {
+                    println!("{} {} ({reason})", "skipped".yellow(), entry.signature())
+                }
+            }
+        }
+        Ok(corrupt)
+    }
     /// backup for if the threaded one is being stupid, not actually intended to be used
     pub fn realize_derivation_sequential(
         &self,
@@ -111,10 +587,47 @@ impl Store {
             realized.push(store_path);
             new_derivations.push(new_derivation);
         }
+        // trees extracted or verified here can be pinned for substituters and closures
+        for (store_path, derivation) in realized.iter().zip(new_derivations.iter_mut()) {
+            if derivation.extract && derivation.content_hash.is_none() {
+                derivation.content_hash = store_path.content_hash();
+            }
+        }
         Ok((realized, new_derivations))
     }
 }
 
+/// how store artifacts are deployed into a target directory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeployMode {
+    Symlink,
+    Copy,
+    Hardlink,
+}
+
+impl DeployMode {
+    pub fn parse(mode: &str) -> Result<Self, String> {
+        match mode {
+            "symlink" => Ok(Self::Symlink),
+            "copy" => Ok(Self::Copy),
+            "hardlink" => Ok(Self::Hardlink),
+            _ => Err(format!(
+                "unknown deploy mode `{mode}` (expected symlink, copy or hardlink)"
+            )),
+        }
+    }
+    pub fn platform_default() -> Self {
+        #[cfg(target_os = "windows")]
+        {
+            Self::Copy
+        }
+        #[cfg(not(target_os = "windows"))]
+        {
+            Self::Symlink
+        }
+    }
+}
+
 pub struct StorePath {
     path: String,
     name: String,
@@ -133,6 +646,14 @@ impl Debug for StorePath {
     }
 }
 
+/// result of checking a store entry against the hash in its path
+pub enum Integrity {
+    Valid,
+    Corrupt { actual: String },
+    Missing,
+    Unverifiable(String),
+}
+
 impl StorePath {
     pub fn new(path: &str, name: &str, hash: &str) -> Self {
         Self {
@@ -144,13 +665,69 @@ impl StorePath {
     fn exists(&self) -> bool {
         Path::new(&self.path).exists()
     }
+    /// file name the artifact is deployed as
+    pub fn name(&self) -> &str {
+        &self.name
+    }
     pub fn get_artifact(&self) -> String {
         format!("{}/artifact", self.path)
     }
+    /// `<hash>-<name>` of this entry
+    pub fn signature(&self) -> String {
+        Path::new(&self.path)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+    fn content_hash_file(&self) -> String {
+        format!("{}/content-hash", self.path)
+    }
+    /// hash of an extracted directory artifact, recorded when it was added to the store
+    pub fn content_hash(&self) -> Option<String> {
+        fs::read_to_string(self.content_hash_file())
+            .ok()
+            .map(|h| h.trim().to_string())
+    }
+    /// re-hashes the artifact and compares it to the hash the entry is stored under
+    pub fn verify(&self) -> Result<Integrity, String> {
+        let artifact = self.get_artifact();
+        let path = Path::new(&artifact);
+        if !path.exists() {
+            Ok(Integrity::Missing)
+        } else if path.is_dir() {
+            let Some(expected) = self.content_hash() else {
+                return Ok(Integrity::Unverifiable(
+                    "no content hash recorded for directory artifact".to_string(),
+                ));
+            };
+            let actual = hash_directory(path)?;
+            if actual == expected {
+                Ok(Integrity::Valid)
+            } else {
+                Ok(Integrity::Corrupt { actual })
+            }
+        } else {
+            let bytes = fs::read(path)
+                .map_err(|e| format!("failed to read artifact `{artifact}` for hashing: {e}"))?;
+            let actual = hash_stream(&bytes);
+            if actual == self.hash {
+                Ok(Integrity::Valid)
+            } else {
+                Ok(Integrity::Corrupt { actual })
+            }
+        }
+    }
+    /// deletes the entry from the store
+    pub fn remove(&self) -> Result<(), String> {
+        // read-only artifacts cannot be deleted on every platform
+        let _ = set_readonly(Path::new(&self.get_artifact()), false);
+        fs::remove_dir_all(&self.path)
+            .map_err(|e| format!("failed to remove store entry `{}`: {e}", self.path))
+    }
     pub fn copy_to(&self, dest: &str) -> Result<(), String> {
         let artifact = self.get_artifact();
         let path = Path::new(&artifact);
-        println!("copying {artifact} -> {dest}");
+        progress::log(&format!("copying {artifact} -> {dest}"));
         if path.is_dir() {
             copy_dir::copy_dir(&artifact, dest).map_err(|e| {
                 format!("failed to copy artifact (`{artifact}`) to dest (`{dest}`): {e}")
@@ -160,12 +737,42 @@ impl StorePath {
                 format!("failed to copy artifact (`{artifact}`) to dest (`{dest}`): {e}")
             })?;
         }
+        // copies are owned by the game dir, so they should not inherit the store's read-only bit
+        set_readonly(Path::new(dest), false)
+            .map_err(|e| format!("failed to make copied artifact `{dest}` writable: {e}"))?;
         Ok(())
     }
+    /// hardlinks every file of the artifact into dest, falling back to a copy when the
+    /// store and dest are on different filesystems
+    pub fn hardlink_to(&self, dest: &str) -> Result<(), String> {
+        let artifact = self.get_artifact();
+        progress::log(&format!("hardlinking {artifact} -> {dest}"));
+        // the target shares inodes with the store, so edits there must not be possible
+        set_readonly(Path::new(&artifact), true)
+            .map_err(|e| format!("failed to make store artifact `{artifact}` read-only: {e}"))?;
+        match hardlink_tree(Path::new(&artifact), Path::new(dest)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                progress::log(
+                    &format!(
+                        "warning: cannot hardlink `{artifact}` to `{dest}` across filesystems, copying instead"
+                    )
+                    .yellow(),
+                );
+                if Path::new(dest).exists() {
+                    remove_fs_entity(dest)?;
+                }
+                self.copy_to(dest)
+            }
+            Err(e) => Err(format!(
+                "failed to hardlink `{artifact}` to `{dest}`: {e} (try passing the --copy flag to copy instead of hardlink.)"
+            )),
+        }
+    }
     #[cfg(target_os = "windows")]
     pub fn symlink_to(&self, dest: &str) -> Result<(), String> {
         let artifact = self.get_artifact();
-        println!("symlinking {artifact} -> {dest}");
+        progress::log(&format!("symlinking {artifact} -> {dest}"));
         let path = Path::new(&artifact);
         if path.is_dir() {
             std::os::windows::fs::symlink_dir(&artifact, dest).map_err(|e|format!("failed to symlink dir `{artifact}` to `{dest}`: {e} (try passing the --copy flag to copy instead of symlink.)"))?;
@@ -178,25 +785,32 @@ impl StorePath {
     #[cfg(unix)]
     pub fn symlink_to(&self, dest: &str) -> Result<(), String> {
         let artifact = self.get_artifact();
-        println!("symlinking {artifact} -> {dest}");
+        progress::log(&format!("symlinking {artifact} -> {dest}"));
         std::os::unix::fs::symlink(&artifact, dest).map_err(|e|format!("failed to symlink dir `{artifact}` to `{dest}`: {e} (try passing the --copy flag to copy instead of symlink.)"))?;
         Ok(())
     }
 
-    pub fn install_to(&self, dest_dir: &str, symlink: bool) -> Result<(), String> {
+    pub fn install_to(&self, dest_dir: &str, mode: DeployMode) -> Result<(), String> {
         fs::create_dir_all(dest_dir)
             .map_err(|e| format!("failed to create destination `{dest_dir}`: {e}"))?;
 
         let dest = format!("{dest_dir}/{}", self.name);
         remove_fs_entity(&dest);
-        if symlink {
-            self.symlink_to(&dest)
-        } else {
-            self.copy_to(&dest)
-        }
+        match mode {
+            DeployMode::Symlink => self.symlink_to(&dest),
+            DeployMode::Copy => self.copy_to(&dest),
+            DeployMode::Hardlink => self.hardlink_to(&dest),
+        }?;
+        progress().advance();
+        Ok(())
     }
 }
 
+/// whether a substituter is reached over the network rather than the filesystem
+fn is_remote(substituter: &str) -> bool {
+    substituter.starts_with("http://") || substituter.starts_with("https://")
+}
+
 fn remove_fs_entity(p: &str) -> Result<(), String> {
     let path = Path::new(p);
     if path.is_dir() {
@@ -207,3 +821,43 @@ fn remove_fs_entity(p: &str) -> Result<(), String> {
     .map_err(|e| format!("failed to remove dir/file `{p}`: {e}"))?;
     Ok(())
 }
+
+/// recreates the directory structure of src at dest, hardlinking each file
+fn hardlink_tree(src: &Path, dest: &Path) -> io::Result<()> {
+    if src.is_dir() {
+        fs::create_dir(dest)?;
+        for entry in src.read_dir()? {
+            let entry = entry?;
+            hardlink_tree(&entry.path(), &dest.join(entry.file_name()))?;
+        }
+        Ok(())
+    } else {
+        fs::hard_link(src, dest)
+    }
+}
+
+/// recursively sets or clears the write bit of every file under p, directories are left as is
+pub fn set_readonly(p: &Path, readonly: bool) -> io::Result<()> {
+    if p.is_dir() {
+        for entry in p.read_dir()? {
+            set_readonly(&entry?.path(), readonly)?;
+        }
+        return Ok(());
+    }
+    let mut permissions = fs::metadata(p)?.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = permissions.mode();
+        permissions.set_mode(if readonly {
+            mode & !0o222
+        } else {
+            mode | 0o200
+        });
+    }
+    #[cfg(not(unix))]
+    {
+        permissions.set_readonly(readonly);
+    }
+    fs::set_permissions(p, permissions)
+}
@@ -0,0 +1,137 @@
+// index of store entries, kept as json next to the store
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+
+use chrono::Utc;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::package::Derivation;
+
+const DB_FILE: &str = "db.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoreEntry {
+    pub name: String,
+    pub file_name: String,
+    pub url: String,
+    /// hash of the downloaded archive, also the hash in the store path
+    pub archive_hash: String,
+    /// hash of the extracted tree for directory artifacts
+    pub content_hash: Option<String>,
+    pub size: u64,
+    pub added: String,
+    /// deployments (`<manifest> -> <target>`) whose last compose used this entry, entries
+    /// recorded before composes were told apart by target hold only the manifest
+    #[serde(default)]
+    pub packs: BTreeSet<String>,
+}
+
+impl StoreEntry {
+    pub fn new(derivation: &Derivation, content_hash: Option<String>, size: u64) -> Self {
+        Self {
+            name: derivation.name.clone(),
+            file_name: derivation.file_name.clone(),
+            url: derivation.url.clone(),
+            archive_hash: derivation.hash.clone().unwrap_or_default(),
+            content_hash,
+            size,
+            added: Utc::now().to_rfc3339(),
+            packs: BTreeSet::new(),
+        }
+    }
+    /// rebuilds a derivation capable of re-fetching this entry from its origin
+    pub fn to_derivation(&self) -> Derivation {
+        Derivation::new(
+            &self.url,
+            &self.name,
+            &self.file_name,
+            self.content_hash.is_some(),
+            None,
+            Some(self.archive_hash.clone()),
+            vec![],
+            vec![],
+            None,
+            None,
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct StoreDb {
+    /// keyed by `<hash>-<name>`
+    pub entries: BTreeMap<String, StoreEntry>,
+}
+
+impl StoreDb {
+    pub fn load(store_path: &str) -> Result<Self, String> {
+        let path = format!("{store_path}/{DB_FILE}");
+        if !Path::new(&path).exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("failed to read store database `{path}`: {e}"))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("failed to parse store database `{path}`: {e}"))
+    }
+    /// writes to a temporary file first so a crash never leaves a truncated database
+    pub fn save(&self, store_path: &str) -> Result<(), String> {
+        fs::create_dir_all(store_path)
+            .map_err(|e| format!("failed to create store `{store_path}`: {e}"))?;
+        let path = format!("{store_path}/{DB_FILE}");
+        let tmp = format!("{path}.tmp");
+        let serialized = serde_json::to_string_pretty(self)
+            .map_err(|e| format!("failed to serialize store database: {e}"))?;
+        fs::write(&tmp, serialized)
+            .map_err(|e| format!("failed to write store database `{tmp}`: {e}"))?;
+        fs::rename(&tmp, &path)
+            .map_err(|e| format!("failed to replace store database `{path}`: {e}"))
+    }
+    /// adds or refreshes an entry, keeping the packs already referencing it
+    pub fn record(&mut self, signature: &str, mut entry: StoreEntry) {
+        if let Some(existing) = self.entries.get(signature) {
+            entry.packs = existing.packs.clone();
+            entry.added = existing.added.clone();
+        }
+        self.entries.insert(signature.to_string(), entry);
+    }
+    /// makes the deployment of `pack` to `target` reference exactly `signatures`, composes of
+    /// the same pack to other targets keep their own references
+    pub fn set_pack_references(&mut self, pack: &str, target: &str, signatures: &BTreeSet<String>) {
+        let reference = format!("{pack} -> {target}");
+        for (signature, entry) in self.entries.iter_mut() {
+            if signatures.contains(signature) {
+                entry.packs.insert(reference.clone());
+            } else {
+                entry.packs.remove(&reference);
+            }
+        }
+    }
+    /// drops references from packs whose manifest or target no longer exists
+    pub fn prune_missing_packs(&mut self) -> Vec<String> {
+        let mut pruned = BTreeSet::new();
+        for entry in self.entries.values_mut() {
+            entry.packs.retain(|pack| {
+                let exists = match pack.split_once(" -> ") {
+                    Some((manifest, target)) => {
+                        Path::new(manifest).exists() && Path::new(target).exists()
+                    }
+                    None => Path::new(pack).exists(),
+                };
+                if !exists {
+                    pruned.insert(pack.clone());
+                }
+                exists
+            });
+        }
+        pruned.into_iter().collect()
+    }
+    pub fn find_by_name(&self, name: &str) -> Vec<(&String, &StoreEntry)> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| entry.name.contains(name))
+            .collect()
+    }
+}
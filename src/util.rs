@@ -1,14 +1,26 @@
 use chrono::Utc;
 use colorize::AnsiColor;
-use sha2::{Digest, Sha256, Sha512};
+use sha2::{Digest, Sha256};
 use std::{
-    fs,
-    io::{Write, stdin, stdout},
+    fs::{self, File},
+    io::{self, Write, stdin, stdout},
     path::Path,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 use crate::package::Derivation;
 
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// forbids network access for the rest of the process
+pub fn set_offline(offline: bool) {
+    OFFLINE.store(offline, Ordering::Relaxed);
+}
+
+pub fn is_offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
+}
+
 pub fn update_derives(
     derivations: &[Derivation],
     backup_dir: &str,
@@ -22,6 +34,25 @@ pub fn update_derives(
     Ok(())
 }
 
+/// backs up the pack then deletes the derivation files of derivations
+pub fn remove_derives(
+    derivations: &[&Derivation],
+    backup_dir: &str,
+    dir: &str,
+    pack_name: &str,
+) -> Result<(), String> {
+    backup_derives(pack_name, dir, backup_dir)?;
+    for derivation in derivations {
+        fs::remove_file(&derivation.backing_file).map_err(|e| {
+            format!(
+                "failed to remove derivation `{}`: {e}",
+                derivation.backing_file
+            )
+        })?;
+    }
+    Ok(())
+}
+
 pub fn backup_derives(pack_name: &str, pack_dir: &str, backup_dir: &str) -> Result<(), String> {
     fs::create_dir_all(backup_dir).map_err(|e| format!("failed to create `{backup_dir}`: {e}"))?;
     let path = Path::new(pack_dir);
@@ -105,23 +136,85 @@ pub fn select_index(
     }
 }
 
-pub fn verify_hash(bytes: &[u8], hash: &str, hashfmt: &str) -> Result<bool, String> {
-    match hashfmt {
-        "nix" => {
-            let hashed = hash_stream(bytes);
-            if hash == hashed { Ok(true) } else { Ok(false) }
-        }
-        "sha512" => {
-            let hashed = format!("{:x}", Sha512::digest(bytes));
-            // .map_err(|e| format!("failed to convert sha512 hash to string {e}"))?;
-            // println!("hash1: {hash}\nhash2: {hashed}");
-            if hash == hashed { Ok(true) } else { Ok(false) }
+pub fn hash_stream(byte_stream: &[u8]) -> String {
+    nix_base32::to_nix_base32(&Sha256::digest(byte_stream)[..])
+}
+
+/// deterministic hash of a directory tree, similar to a nix NAR hash
+///
+/// entries are visited in sorted order and each contributes its relative path, its type, the
+/// executable bit for files and the file contents or link target, so the result only depends on
+/// the tree itself and not on the archive it was extracted from.
+pub fn hash_directory(dir: &Path) -> Result<String, String> {
+    let mut hasher = Sha256::new();
+    hash_tree_into(dir, "", &mut hasher)
+        .map_err(|e| format!("failed to hash directory `{}`: {e}", dir.display()))?;
+    Ok(nix_base32::to_nix_base32(&hasher.finalize()[..]))
+}
+
+fn hash_tree_into(dir: &Path, prefix: &str, hasher: &mut Sha256) -> io::Result<()> {
+    let mut entries = dir.read_dir()?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let relative = format!("{prefix}{}", entry.file_name().to_string_lossy());
+        let file_type = entry.file_type()?;
+        hasher.update(relative.as_bytes());
+        hasher.update([0]);
+        if file_type.is_symlink() {
+            hasher.update(b"symlink\0");
+            hasher.update(fs::read_link(entry.path())?.to_string_lossy().as_bytes());
+        } else if file_type.is_dir() {
+            hasher.update(b"dir\0");
+            hash_tree_into(&entry.path(), &format!("{relative}/"), hasher)?;
+        } else {
+            let metadata = entry.metadata()?;
+            hasher.update(b"file\0");
+            hasher.update(if is_executable(&metadata) { b"x" } else { b"-" });
+            hasher.update(metadata.len().to_le_bytes());
+            io::copy(&mut File::open(entry.path())?, hasher)?;
         }
+    }
+    Ok(())
+}
 
-        _ => Err(format!("unknown hash format {hashfmt}")),
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
     }
 }
 
-pub fn hash_stream(byte_stream: &[u8]) -> String {
-    nix_base32::to_nix_base32(&Sha256::digest(byte_stream)[..])
+/// total size in bytes of a file or every file below a directory
+pub fn disk_size(p: &Path) -> u64 {
+    if p.is_dir() {
+        p.read_dir()
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| disk_size(&e.path()))
+                    .sum()
+            })
+            .unwrap_or(0)
+    } else {
+        fs::symlink_metadata(p).map(|m| m.len()).unwrap_or(0)
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    false
 }